@@ -0,0 +1,337 @@
+use bdk_wallet::bitcoin::{Address, Amount, Psbt, Script, SignedAmount, Transaction, TxOut};
+use bitcoincore_rpc::{Client, RpcApi};
+
+use crate::{
+    client::get_client_balance,
+    config::config,
+    payjoin::{forwarding::Forward, sender::SendOutcome},
+    weight::spent_output,
+};
+
+/// Network fee a participant may end up paying.
+#[derive(Debug, Clone, Copy)]
+pub enum NetworkFee {
+    /// Someone else pays for its inputs and outputs
+    None,
+    /// At most this much (i.e. the original fee plus the max fee contribution of a payjoin sender)
+    UpTo(Amount),
+    /// The whole tx fee
+    All,
+    /// Whatever the others don't pay
+    Rest,
+}
+
+struct Participant {
+    name: String,
+    /// Payment received (+) or sent (-)
+    amount: SignedAmount,
+    /// Participant fees earned (+) or paid (-)
+    participant_fee: SignedAmount,
+    network_fee: NetworkFee,
+    balance_before: Amount,
+    /// Tell its scripts apart (wallet `is_mine`), only its balance is known otherwise
+    tracked: bool,
+    /// Net change in the tx, from the inputs and outputs it owns
+    net: SignedAmount,
+}
+
+impl Participant {
+    fn expected(&self) -> SignedAmount {
+        self.amount + self.participant_fee
+    }
+}
+
+/// Expected net change of every participant of a scenario: payment, promised participant fee and
+/// share of the network fee. Checked against the final tx, then against the wallet balances once
+/// it confirmed, so the demos fail instead of printing wrong numbers.
+#[derive(Default)]
+pub struct Accounting {
+    participants: Vec<Participant>,
+    tx_fee: Amount,
+    /// Net value the tx moves to (+) or from (-) anyone not tracked
+    others: SignedAmount,
+}
+
+impl Accounting {
+    pub fn new() -> Accounting {
+        Accounting::default()
+    }
+
+    /// Adds a participant, `tracked` when `record_tx` can tell which scripts are its own. Returns
+    /// its index (what `record_tx`'s `owner` returns).
+    pub fn participant(
+        &mut self,
+        name: impl Into<String>,
+        amount: SignedAmount,
+        participant_fee: SignedAmount,
+        network_fee: NetworkFee,
+        balance_before: Amount,
+        tracked: bool,
+    ) -> usize {
+        self.participants.push(Participant {
+            name: name.into(),
+            amount,
+            participant_fee,
+            network_fee,
+            balance_before,
+            tracked,
+            net: SignedAmount::ZERO,
+        });
+        self.participants.len() - 1
+    }
+
+    /// Splits the final tx between the participants: `owner` gives the (tracked) participant a
+    /// script belongs to, `prevouts` are the outputs its inputs spend, in order.
+    pub fn record_tx(
+        &mut self,
+        tx: &Transaction,
+        prevouts: &[TxOut],
+        mut owner: impl FnMut(&Script) -> Result<Option<usize>, Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if prevouts.len() != tx.input.len() {
+            return Err("One prevout per input is needed".into());
+        }
+        let spent: Amount = prevouts.iter().map(|txout| txout.value).sum();
+        let paid: Amount = tx.output.iter().map(|txout| txout.value).sum();
+        self.tx_fee = spent
+            .checked_sub(paid)
+            .ok_or("Tx spends more than its inputs")?;
+
+        let flows = prevouts
+            .iter()
+            .map(|txout| (txout, -signed(txout.value)))
+            .chain(tx.output.iter().map(|txout| (txout, signed(txout.value))));
+        for (txout, value) in flows {
+            match owner(&txout.script_pubkey)? {
+                Some(idx) => {
+                    let participant = self
+                        .participants
+                        .get_mut(idx)
+                        .ok_or("Unknown participant")?;
+                    participant.net += value;
+                }
+                None => self.others += value,
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the expected changes with the tx and the balances after it confirmed (same order
+    /// as the participants), prints the report and fails on any mismatch.
+    pub fn verify(&self, balances_after: &[Amount]) -> Result<(), Box<dyn std::error::Error>> {
+        if balances_after.len() != self.participants.len() {
+            return Err("One balance per participant is needed".into());
+        }
+        println!(
+            "[Accounting] Tx fee: {} | others: {}",
+            self.tx_fee, self.others
+        );
+        let mut mismatches = vec![];
+        for (participant, after) in self.participants.iter().zip(balances_after) {
+            let actual = signed(*after) - signed(participant.balance_before);
+            let result = if participant.tracked {
+                self.check_tracked(participant, actual)
+            } else {
+                self.check_untracked(participant, actual)
+            };
+            println!(
+                "[Accounting] {}: payment {} | participant fees {} | network fee {:?} | tx {} | wallet {} -> {}",
+                participant.name,
+                participant.amount,
+                participant.participant_fee,
+                participant.network_fee,
+                if participant.tracked {
+                    participant.net.to_string()
+                } else {
+                    "-".to_string()
+                },
+                actual,
+                match &result {
+                    Ok(()) => "OK".to_string(),
+                    Err(reason) => format!("MISMATCH: {}", reason),
+                }
+            );
+            if let Err(reason) = result {
+                mismatches.push(format!("{} ({})", participant.name, reason));
+            }
+        }
+        if !mismatches.is_empty() {
+            return Err(format!("Balance mismatch: {}", mismatches.join(", ")).into());
+        }
+        Ok(())
+    }
+
+    fn all_tracked(&self) -> bool {
+        self.others == SignedAmount::ZERO && self.participants.iter().all(|p| p.tracked)
+    }
+
+    /// Network fees the tracked participants pay, besides `except`.
+    fn fees_of_others(&self, except: &Participant) -> SignedAmount {
+        self.participants
+            .iter()
+            .filter(|p| p.tracked && !std::ptr::eq(*p, except))
+            .map(|p| p.expected() - p.net)
+            .sum()
+    }
+
+    fn check_tracked(&self, participant: &Participant, actual: SignedAmount) -> Result<(), String> {
+        let fee_paid = participant.expected() - participant.net;
+        let tx_fee = signed(self.tx_fee);
+        let fee_ok = match participant.network_fee {
+            NetworkFee::None => fee_paid == SignedAmount::ZERO,
+            NetworkFee::UpTo(max) => fee_paid >= SignedAmount::ZERO && fee_paid <= signed(max),
+            NetworkFee::All => fee_paid == tx_fee,
+            // Exact only when the whole tx is split between tracked participants
+            NetworkFee::Rest if self.all_tracked() => {
+                fee_paid == tx_fee - self.fees_of_others(participant)
+            }
+            NetworkFee::Rest => fee_paid >= SignedAmount::ZERO && fee_paid <= tx_fee,
+        };
+        if !fee_ok {
+            return Err(format!("the tx makes it pay {} of network fee", fee_paid));
+        }
+        if actual != participant.net {
+            return Err(format!(
+                "wallet changed by {}, the tx by {}",
+                actual, participant.net
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_untracked(
+        &self,
+        participant: &Participant,
+        actual: SignedAmount,
+    ) -> Result<(), String> {
+        let expected = participant.expected();
+        let fee_paid = expected - actual;
+        let ok = match participant.network_fee {
+            NetworkFee::None => fee_paid == SignedAmount::ZERO,
+            NetworkFee::UpTo(max) => fee_paid >= SignedAmount::ZERO && fee_paid <= signed(max),
+            NetworkFee::All => fee_paid == signed(self.tx_fee),
+            NetworkFee::Rest => fee_paid >= SignedAmount::ZERO && fee_paid <= signed(self.tx_fee),
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!(
+                "expected {} before network fee, wallet changed by {}",
+                expected, actual
+            ))
+        }
+    }
+}
+
+fn signed(amount: Amount) -> SignedAmount {
+    SignedAmount::from_sat(amount.to_sat() as i64)
+}
+
+/// Outputs the PSBT inputs spend, from their UTXO fields.
+pub fn psbt_prevouts(psbt: &Psbt) -> Result<Vec<TxOut>, Box<dyn std::error::Error>> {
+    psbt.inputs
+        .iter()
+        .zip(&psbt.unsigned_tx.input)
+        .map(|(input, txin)| spent_output(input, txin).cloned())
+        .collect()
+}
+
+/// Outputs the tx inputs spend, looked up in the bitcoind wallets that may have funded them.
+pub fn wallet_prevouts(
+    tx: &Transaction,
+    wallets: &[&Client],
+) -> Result<Vec<TxOut>, Box<dyn std::error::Error>> {
+    let mut prevouts = Vec::with_capacity(tx.input.len());
+    for txin in &tx.input {
+        let outpoint = txin.previous_output;
+        let prevout = wallets
+            .iter()
+            .find_map(|wallet| {
+                let funding = wallet.get_transaction(&outpoint.txid, None).ok()?;
+                funding
+                    .transaction()
+                    .ok()?
+                    .output
+                    .get(outpoint.vout as usize)
+                    .cloned()
+            })
+            .ok_or_else(|| format!("No wallet knows the output {}", outpoint))?;
+        prevouts.push(prevout);
+    }
+    Ok(prevouts)
+}
+
+/// Whether the script pays the bitcoind wallet.
+pub fn client_owns(client: &Client, script: &Script) -> Result<bool, Box<dyn std::error::Error>> {
+    let Ok(address) = Address::from_script(script, config().network) else {
+        return Ok(false);
+    };
+    Ok(client.get_address_info(&address)?.is_mine.unwrap_or(false))
+}
+
+/// Checks a payjoin between bitcoind wallets once its tx (the payjoin, or the original tx)
+/// confirmed: the sender pays `amount` plus at most `sender_max_fee` (original fee and fee
+/// contribution), the receiver gets it minus what the tx forwards to its creditors and pays the
+/// rest of the fee. A payjoin must forward all of `forwards` when output substitution is allowed
+/// (by the receiver policy and the sender).
+#[allow(clippy::too_many_arguments)]
+pub fn verify_payjoin(
+    sender: &Client,
+    receiver: &Client,
+    balances_before: [Amount; 2],
+    outcome: &SendOutcome,
+    amount: Amount,
+    sender_max_fee: Amount,
+    forwards: &[Forward],
+    sender_disabled_substitution: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = outcome.tx();
+    let (paid, missing): (Vec<&Forward>, Vec<&Forward>) = forwards.iter().partition(|forward| {
+        let script = forward.address.assume_checked_ref().script_pubkey();
+        tx.output
+            .iter()
+            .any(|txout| txout.script_pubkey == script && txout.value == forward.amount)
+    });
+    if matches!(outcome, SendOutcome::Payjoin(_))
+        && config().receiver_policy.allow_output_substitution
+        && !sender_disabled_substitution
+        && !missing.is_empty()
+    {
+        return Err(format!(
+            "The payjoin misses {} of the {} forward(s)",
+            missing.len(),
+            forwards.len()
+        )
+        .into());
+    }
+    let forwarded: Amount = paid.iter().map(|forward| forward.amount).sum();
+    let payment = amount.to_signed()?;
+
+    let mut accounting = Accounting::new();
+    let snd = accounting.participant(
+        "sender",
+        -payment,
+        SignedAmount::ZERO,
+        NetworkFee::UpTo(sender_max_fee),
+        balances_before[0],
+        true,
+    );
+    let rcv = accounting.participant(
+        "receiver",
+        payment - forwarded.to_signed()?,
+        SignedAmount::ZERO,
+        NetworkFee::Rest,
+        balances_before[1],
+        true,
+    );
+    accounting.record_tx(tx, &wallet_prevouts(tx, &[sender, receiver])?, |script| {
+        Ok(if client_owns(sender, script)? {
+            Some(snd)
+        } else if client_owns(receiver, script)? {
+            Some(rcv)
+        } else {
+            None
+        })
+    })?;
+    accounting.verify(&[get_client_balance(sender)?, get_client_balance(receiver)?])
+}
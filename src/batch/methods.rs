@@ -1,17 +1,24 @@
 use bdk_wallet::{
     bitcoin::{
         locktime::absolute::LockTime,
-        policy::DEFAULT_MIN_RELAY_TX_FEE,
         psbt::{Input, Output, Psbt},
-        Amount, FeeRate, ScriptBuf, Transaction, TxIn, TxOut, Weight,
+        Amount, FeeRate, ScriptBuf, SignedAmount, TxIn, TxOut,
     },
     KeychainKind, LocalOutput, SignOptions, Wallet,
 };
 use bitcoincore_rpc::{Client, RpcApi};
 
 use crate::{
+    accounting::{psbt_prevouts, Accounting, NetworkFee},
+    cli::{effective_fee_rate, BatchArgs},
     client::wait_for_block,
-    wallet::{create_wallet, fund_wallet, get_wallet_utxos, sync_wallet, wallet_total_balance},
+    seed::node_seed,
+    wallet::{
+        create_descriptor_wallet, create_wallet, fund_wallet, get_wallet_utxos, participant_wallet,
+        psbt_input, read_descriptor, sync_wallet, wallet_name, wallet_total_balance, ScriptType,
+        StoredWallet,
+    },
+    weight::{predict_from_descriptor, FeeCheck, Signatures},
 };
 
 pub fn add_utxos_to_psbt(
@@ -41,29 +48,15 @@ pub fn add_utxos_to_psbt(
             "[Batch] Adding UTXO [txid={:?} | vout={:?}]",
             utxo.outpoint.txid, utxo.outpoint.vout
         );
-        if let Some(canonical_tx) = wallet
-            .transactions()
-            .find(|tx| tx.tx_node.compute_txid() == utxo.outpoint.txid)
-        {
-            let tx = (*canonical_tx.tx_node.tx).clone();
-            let input = TxIn {
-                previous_output: utxo.outpoint,
-                script_sig: Default::default(),
-                sequence: Default::default(),
-                witness: Default::default(),
-            };
-            psbt.inputs.push(Input {
-                non_witness_utxo: Some(tx),
-                ..Default::default()
-            });
-            psbt.unsigned_tx.input.push(input);
-            utxos_total_value += utxo.txout.value;
-
-            count += 1;
-            if count >= max_count {
-                break;
-            }
-        };
+        let (txin, input) = psbt_input(wallet, &utxo)?;
+        psbt.inputs.push(input);
+        psbt.unsigned_tx.input.push(txin);
+        utxos_total_value += utxo.txout.value;
+
+        count += 1;
+        if count >= max_count {
+            break;
+        }
     }
 
     let mut value = utxos_total_value;
@@ -106,6 +99,7 @@ pub fn add_utxos_to_psbt(
 fn add_utxos(
     wallet: &mut Wallet,
     psbt_hex: String,
+    max_count: u16,
     fee: Amount,
     payer: bool,
 ) -> Result<String, Box<dyn std::error::Error>> {
@@ -130,29 +124,15 @@ fn add_utxos(
             "[Batch] Adding UTXO [txid={:?} | vout={:?}]",
             utxo.outpoint.txid, utxo.outpoint.vout
         );
-        if let Some(canonical_tx) = wallet
-            .transactions()
-            .find(|tx| tx.tx_node.compute_txid() == utxo.outpoint.txid)
-        {
-            let tx = (*canonical_tx.tx_node.tx).clone();
-            let input = TxIn {
-                previous_output: utxo.outpoint,
-                script_sig: Default::default(),
-                sequence: Default::default(),
-                witness: Default::default(),
-            };
-            psbt.inputs.push(Input {
-                non_witness_utxo: Some(tx),
-                ..Default::default()
-            });
-            psbt.unsigned_tx.input.push(input);
-            receiver_utxos_value += utxo.txout.value;
-
-            count += 1;
-            if count >= 1 {
-                break;
-            }
-        };
+        let (txin, input) = psbt_input(wallet, &utxo)?;
+        psbt.inputs.push(input);
+        psbt.unsigned_tx.input.push(txin);
+        receiver_utxos_value += utxo.txout.value;
+
+        count += 1;
+        if count >= max_count {
+            break;
+        }
     }
 
     let script_pubkey = wallet
@@ -181,13 +161,14 @@ fn add_utxos(
 
 fn add_utxos_from_pool(
     psbt: &mut Psbt,
-    utxos: Vec<(LocalOutput, Transaction)>,
+    utxos: Vec<(LocalOutput, TxIn, Input)>,
     script_pubkey: ScriptBuf,
+    max_count: u16,
     fee: Amount,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut count = 0;
     let mut receiver_utxos_value = Amount::from_sat(0);
-    for (utxo, tx) in utxos {
+    for (utxo, txin, input) in utxos {
         let mut inserted = false;
         for input in psbt.unsigned_tx.input.clone() {
             if input.previous_output.txid == utxo.outpoint.txid
@@ -204,21 +185,12 @@ fn add_utxos_from_pool(
             utxo.outpoint.txid, utxo.outpoint.vout
         );
 
-        let input = TxIn {
-            previous_output: utxo.outpoint,
-            script_sig: Default::default(),
-            sequence: Default::default(),
-            witness: Default::default(),
-        };
-        psbt.inputs.push(Input {
-            non_witness_utxo: Some(tx),
-            ..Default::default()
-        });
-        psbt.unsigned_tx.input.push(input);
+        psbt.inputs.push(input);
+        psbt.unsigned_tx.input.push(txin);
         receiver_utxos_value += utxo.txout.value;
 
         count += 1;
-        if count >= 2 {
+        if count >= max_count {
             break;
         }
     }
@@ -239,11 +211,11 @@ pub fn build_psbt(
     sender: &mut Wallet,
     script_pubkey: ScriptBuf,
     amount: Amount,
+    fee_rate: FeeRate,
     count: usize,
 ) -> Result<Psbt, Box<dyn std::error::Error>> {
     let utxos = get_wallet_utxos(sender);
 
-    let fee_rate = FeeRate::from_sat_per_vb(DEFAULT_MIN_RELAY_TX_FEE as u64).unwrap();
     let locktime = LockTime::ZERO;
 
     let mut builder = sender.build_tx();
@@ -265,6 +237,129 @@ pub fn build_psbt(
     Ok(psbt)
 }
 
+/// The sender's PSBT only pays the network fee of its own inputs and outputs: the rest, for what
+/// the nodes added, is taken from the payer's (fee-cover) output.
+fn pay_network_fee(
+    psbt: &mut Psbt,
+    payer_output: usize,
+    fee_rate: FeeRate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let missing = FeeCheck::new(psbt, Signatures::Max)?.missing(fee_rate)?;
+    let output = psbt
+        .unsigned_tx
+        .output
+        .get_mut(payer_output)
+        .ok_or("No payer output in the PSBT")?;
+    output.value = output
+        .value
+        .checked_sub(missing)
+        .ok_or("Payer output can't cover the network fee")?;
+    println!("[Batch] Payer adding {} of network fee", missing);
+    check_network_fee(psbt, fee_rate)
+}
+
+fn check_network_fee(psbt: &Psbt, fee_rate: FeeRate) -> Result<(), Box<dyn std::error::Error>> {
+    let check = FeeCheck::new(psbt, Signatures::Max)?;
+    println!("[Batch] Fee check: {}", check);
+    check.ensure(fee_rate)
+}
+
+/// Payment, participant fee and network fee of a batch participant.
+type Terms = (SignedAmount, SignedAmount, NetworkFee);
+
+/// Registers sender, receiver (gets `amount`, pays no network fee) and nodes, in this order, with
+/// their balances before the batch tx.
+fn batch_accounting(
+    bitcoind: &Client,
+    sender: &mut Wallet,
+    receiver: &mut Wallet,
+    nodes: &mut [StoredWallet],
+    amount: Amount,
+    sender_terms: Terms,
+    node_terms: impl Fn(usize, &Wallet) -> Terms,
+) -> Result<Accounting, Box<dyn std::error::Error>> {
+    let mut accounting = Accounting::new();
+    let (payment, participant_fee, network_fee) = sender_terms;
+    let before = wallet_total_balance(bitcoind, sender)?;
+    accounting.participant(
+        "sender",
+        payment,
+        participant_fee,
+        network_fee,
+        before,
+        true,
+    );
+    let before = wallet_total_balance(bitcoind, receiver)?;
+    accounting.participant(
+        "receiver",
+        amount.to_signed()?,
+        SignedAmount::ZERO,
+        NetworkFee::None,
+        before,
+        true,
+    );
+    for (idx, node) in nodes.iter_mut().enumerate() {
+        let (payment, participant_fee, network_fee) = node_terms(idx, node);
+        let before = wallet_total_balance(bitcoind, node)?;
+        accounting.participant(
+            format!("node {}", idx),
+            payment,
+            participant_fee,
+            network_fee,
+            before,
+            true,
+        );
+    }
+    Ok(accounting)
+}
+
+/// Terms of methods where the sender pays the receiver, a fee to each node and the network fee.
+fn paying_sender_terms(amount: Amount, fee_per_participant: Amount, nodes: u64) -> Terms {
+    (
+        -signed(amount),
+        -signed(fee_per_participant * nodes),
+        NetworkFee::Rest,
+    )
+}
+
+fn signed(amount: Amount) -> SignedAmount {
+    SignedAmount::from_sat(amount.to_sat() as i64)
+}
+
+/// Once the batch tx confirmed: splits it between the participants of `batch_accounting` and
+/// checks their balances.
+fn verify_batch(
+    bitcoind: &Client,
+    mut accounting: Accounting,
+    psbt: &Psbt,
+    sender: &mut Wallet,
+    receiver: &mut Wallet,
+    nodes: &mut [StoredWallet],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = psbt.clone().extract_tx()?;
+    accounting.record_tx(&tx, &psbt_prevouts(psbt)?, |script| {
+        let script = script.to_owned();
+        Ok(if sender.is_mine(script.clone()) {
+            Some(0)
+        } else if receiver.is_mine(script.clone()) {
+            Some(1)
+        } else {
+            nodes
+                .iter()
+                .position(|node| node.is_mine(script.clone()))
+                .map(|idx| idx + 2)
+        })
+    })?;
+    let mut balances = vec![
+        wallet_total_balance(bitcoind, sender)?,
+        wallet_total_balance(bitcoind, receiver)?,
+    ];
+    for node in nodes.iter_mut() {
+        balances.push(wallet_total_balance(bitcoind, node)?);
+    }
+    accounting.verify(&balances)
+}
+
 fn get_input_value(psbt: &Psbt) -> (Amount, Amount) {
     let mut total_witness_utxo = Amount::ZERO;
     let mut total_non_witness_utxo = Amount::ZERO;
@@ -290,29 +385,90 @@ fn get_total_output(psbt: &Psbt) -> Amount {
 
 fn setup(
     bitcoind: &Client,
-    count: u8,
-) -> Result<(Wallet, Wallet, Vec<Wallet>), Box<dyn std::error::Error>> {
+    args: &BatchArgs,
+) -> Result<(StoredWallet, StoredWallet, Vec<StoredWallet>), Box<dyn std::error::Error>> {
     println!("[Batch] Starting...");
+    if args.node_seed_prefix.is_none() {
+        // Nodes use the test seeds 1..=participants, a participant sharing one would share keys
+        let shared = [
+            (
+                args.sender_seed_name.is_none(),
+                args.sender_seed,
+                "--sender-seed",
+            ),
+            (
+                args.receiver_seed_name.is_none() && args.receiver_descriptor.is_none(),
+                args.receiver_seed,
+                "--receiver-seed",
+            ),
+        ];
+        for (test_seed, byte, flag) in shared {
+            if test_seed && (1..=args.participants).contains(&byte) {
+                return Err(format!(
+                    "{} {} is the seed of node {}, pick one above {}",
+                    flag, byte, byte, args.participants
+                )
+                .into());
+            }
+        }
+    }
     let mut nodes = vec![];
-    for idx in 1..=count {
-        nodes.push(create_wallet(&[idx; 64])?);
+    for idx in 1..=args.participants {
+        let script_type = if args.mixed_script_types {
+            ScriptType::ALL[(idx as usize - 1) % ScriptType::ALL.len()]
+        } else {
+            ScriptType::P2wpkh
+        };
+        println!("[Batch] Node {}: {}", idx, script_type);
+        let (label, seed) = node_seed(args.node_seed_prefix.as_deref(), idx)?;
+        nodes.push(create_wallet(
+            &wallet_name("node", &label, script_type),
+            &seed[..],
+            script_type,
+        )?);
     }
 
+    // Long-lived wallets are only topped up when they run low
+    let mut funded = false;
     for mut node in nodes.iter_mut() {
-        fund_wallet(bitcoind, &mut node, Amount::from_sat(1_000_000), 10)?;
+        if wallet_total_balance(bitcoind, node)? < Amount::from_sat(5_000_000) {
+            fund_wallet(bitcoind, &mut node, Amount::from_sat(1_000_000), 10)?;
+            funded = true;
+        }
     }
 
-    let mut sender = create_wallet(&[0u8; 64])?;
-    let receiver = create_wallet(&[7u8; 64])?;
+    let mut sender = participant_wallet(
+        "sender",
+        args.sender_seed_name.as_deref(),
+        args.sender_seed,
+        args.sender_script_type,
+    )?;
+    // The receiver never signs, it can be watch-only
+    let receiver = match &args.receiver_descriptor {
+        Some(descriptor) => create_descriptor_wallet(read_descriptor(descriptor)?, None)?,
+        None => participant_wallet(
+            "receiver",
+            args.receiver_seed_name.as_deref(),
+            args.receiver_seed,
+            args.receiver_script_type,
+        )?,
+    };
 
-    fund_wallet(bitcoind, &mut sender, Amount::from_sat(10_000_000), 4)?;
+    if wallet_total_balance(bitcoind, &mut sender)? < Amount::from_sat(20_000_000) {
+        fund_wallet(bitcoind, &mut sender, Amount::from_sat(10_000_000), 4)?;
+        funded = true;
+    }
 
-    wait_for_block(bitcoind, 3)?;
+    if funded {
+        wait_for_block(bitcoind, 3)?;
+    }
 
     sync_wallet(bitcoind, &mut sender, true)?;
+    sender.persist()?;
 
-    for mut node in nodes.iter_mut() {
-        sync_wallet(bitcoind, &mut node, true)?;
+    for node in nodes.iter_mut() {
+        sync_wallet(bitcoind, node, true)?;
+        node.persist()?;
     }
     Ok((sender, receiver, nodes))
 }
@@ -322,8 +478,8 @@ fn setup(
 //     1 - Circle the origial PSBT between nodes
 //     2 - Each node adds their UTXOs to that PSBT
 //     3 - Once its done the final PSBT is circle between each node so they can sign it
-pub fn method_1(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, 5)?;
+pub fn method_1(bitcoind: &Client, args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, args)?;
 
     println!(
         "[Batch] Sender Balance: {:?}",
@@ -344,19 +500,32 @@ pub fn method_1(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     // Starting the PSBT
     println!("[Batch] Sender PSBT...");
 
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
-    let mut psbt = build_psbt(&mut sender, script_pubkey, amount, 2)?;
+    let mut psbt = build_psbt(
+        &mut sender,
+        script_pubkey,
+        amount,
+        effective_fee_rate(args.fee_rate),
+        2,
+    )?;
 
-    let fee_per_participant = Amount::from_sat(77_777);
+    let fee_per_participant = args.fee_per_participant;
     let participants = nodes.len() as u64;
 
     println!("[Batch] Getting PSBT from Network...");
     for node in nodes.iter_mut() {
-        add_utxos_to_psbt(node, &mut psbt, 2, None, fee_per_participant, false)?;
+        add_utxos_to_psbt(
+            node,
+            &mut psbt,
+            args.max_utxos,
+            None,
+            fee_per_participant,
+            false,
+        )?;
     }
 
     // Check total inputs/outputs amount (DEBUG)
@@ -371,6 +540,8 @@ pub fn method_1(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 
     // To cover fees
     add_utxos_to_psbt(&mut sender, &mut psbt, 2, None, total_fee, true)?;
+    let payer_output = psbt.unsigned_tx.output.len() - 1;
+    pay_network_fee(&mut psbt, payer_output, effective_fee_rate(args.fee_rate))?;
 
     for node in nodes.iter_mut() {
         node.sign(&mut psbt, SignOptions::default()).unwrap();
@@ -381,6 +552,22 @@ pub fn method_1(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     println!("[Batch] Extracting Tx...");
     let tx = psbt.clone().extract_tx()?;
 
+    let accounting = batch_accounting(
+        bitcoind,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+        amount,
+        paying_sender_terms(amount, fee_per_participant, participants),
+        |_, _| {
+            (
+                SignedAmount::ZERO,
+                signed(fee_per_participant),
+                NetworkFee::None,
+            )
+        },
+    )?;
+
     println!("[Batch] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
@@ -402,6 +589,15 @@ pub fn method_1(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
+    verify_batch(
+        bitcoind,
+        accounting,
+        &psbt,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+    )?;
+
     Ok(())
 }
 
@@ -411,25 +607,40 @@ pub fn method_1(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 //     2 - Each node builds their own PSBT
 //     3 - Sender "merge" them into a final PSBT
 //     4 - Once its done the final PSBT is circle between each node so they can sign it
-pub fn method_2(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, 5)?;
+pub fn method_2(bitcoind: &Client, args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, args)?;
     // Starting the PSBT
     println!("[Batch] Sender PSBT...");
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
-    let mut sender_psbt = build_psbt(&mut sender, script_pubkey, amount, 2)?;
+    let mut sender_psbt = build_psbt(
+        &mut sender,
+        script_pubkey,
+        amount,
+        effective_fee_rate(args.fee_rate),
+        2,
+    )?;
+    let sender_fee = sender_psbt.fee()?;
 
     println!("[Batch] Getting PSBT from Network...");
     let mut psbts = vec![];
+    let mut node_fees = vec![];
     for node in nodes.iter_mut() {
         let script_pubkey = node
             .reveal_next_address(KeychainKind::External)
             .address
             .script_pubkey();
-        let psbt = build_psbt(node, script_pubkey, Amount::from_sat(500_000), 2)?;
+        let psbt = build_psbt(
+            node,
+            script_pubkey,
+            Amount::from_sat(500_000),
+            effective_fee_rate(args.fee_rate),
+            args.max_utxos as usize,
+        )?;
+        node_fees.push(psbt.fee()?);
         psbts.push(psbt);
     }
 
@@ -446,6 +657,7 @@ pub fn method_2(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
         sender_psbt.inputs.extend(psbt.inputs.clone());
         sender_psbt.outputs.extend(psbt.outputs.clone());
     }
+    check_network_fee(&sender_psbt, effective_fee_rate(args.fee_rate))?;
 
     sender.sign(&mut sender_psbt, SignOptions::default())?;
     for node in nodes.iter_mut() {
@@ -458,9 +670,41 @@ pub fn method_2(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     let total_output: Amount = tx.output.iter().map(|output| output.value).sum();
     println!("====> Outputs ({})", total_output);
 
+    // Everyone pays the fee of its own PSBT, the nodes pay themselves
+    let accounting = batch_accounting(
+        bitcoind,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+        amount,
+        (
+            -signed(amount),
+            SignedAmount::ZERO,
+            NetworkFee::UpTo(sender_fee),
+        ),
+        |idx, _| {
+            (
+                SignedAmount::ZERO,
+                SignedAmount::ZERO,
+                NetworkFee::UpTo(node_fees[idx]),
+            )
+        },
+    )?;
+
     println!("[Batch] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
+    wait_for_block(bitcoind, 2)?;
+
+    verify_batch(
+        bitcoind,
+        accounting,
+        &sender_psbt,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+    )?;
+
     Ok(())
 }
 
@@ -470,48 +714,38 @@ pub fn method_2(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 //     2 - Each node builds shared their UTXOs
 //     3 - Sender adds the nodes' UTXOs to the original PSBT
 //     4 - Once its done the final PSBT is circle between each node so they can sign it
-pub fn method_3(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, 5)?;
+pub fn method_3(bitcoind: &Client, args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, args)?;
     // Starting the PSBT
     println!("[Batch] Sender PSBT...");
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
 
-    let fee_rate = FeeRate::from_sat_per_vb(DEFAULT_MIN_RELAY_TX_FEE as u64).unwrap();
     let locktime = LockTime::ZERO;
 
     let mut builder = sender.build_tx();
     builder
         .add_recipient(script_pubkey, amount)
-        .fee_rate(fee_rate)
+        .fee_rate(effective_fee_rate(args.fee_rate))
         .nlocktime(locktime);
 
     println!("[Batch] Getting UTXOs from Network...");
     for node in nodes.iter_mut() {
         let utxos = get_wallet_utxos(&node);
         for utxo in utxos {
-            if let Some(canonical_tx) = node
-                .transactions()
-                .find(|tx| tx.tx_node.compute_txid() == utxo.outpoint.txid)
-            {
-                let tx = (*canonical_tx.tx_node.tx).clone();
-                let psbt_input = Input {
-                    non_witness_utxo: Some(tx),
-                    ..Default::default()
-                };
-                let satisfaction_weight = match psbt_input.clone().witness_utxo {
-                    Some(w) => w.weight(),
-                    None => Weight::MIN,
-                };
-                builder.add_foreign_utxo(utxo.outpoint, psbt_input, satisfaction_weight)?;
-            }
+            let (_, input) = psbt_input(node, &utxo)?;
+            let satisfaction_weight =
+                predict_from_descriptor(node.public_descriptor(utxo.keychain), Signatures::Max)?
+                    .weight();
+            builder.add_foreign_utxo(utxo.outpoint, input, satisfaction_weight)?;
         }
     }
 
     let mut sender_psbt = builder.finish()?;
+    check_network_fee(&sender_psbt, effective_fee_rate(args.fee_rate))?;
 
     sender.sign(&mut sender_psbt, SignOptions::default())?;
     for node in nodes.iter_mut() {
@@ -524,9 +758,50 @@ pub fn method_3(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     let total_output: Amount = tx.output.iter().map(|output| output.value).sum();
     println!("====> Outputs ({})", total_output);
 
+    // The nodes' UTXOs are spent to the sender's outputs, it pays the whole network fee
+    let prevouts = psbt_prevouts(&sender_psbt)?;
+    let owned_by = |node: &StoredWallet| -> Amount {
+        prevouts
+            .iter()
+            .filter(|txout| node.is_mine(txout.script_pubkey.clone()))
+            .map(|txout| txout.value)
+            .sum()
+    };
+    let contributed: Amount = nodes.iter().map(owned_by).sum();
+    let accounting = batch_accounting(
+        bitcoind,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+        amount,
+        (
+            signed(contributed) - signed(amount),
+            SignedAmount::ZERO,
+            NetworkFee::All,
+        ),
+        |_, node| {
+            (
+                -signed(owned_by(node)),
+                SignedAmount::ZERO,
+                NetworkFee::None,
+            )
+        },
+    )?;
+
     println!("[Batch] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
+    wait_for_block(bitcoind, 2)?;
+
+    verify_batch(
+        bitcoind,
+        accounting,
+        &sender_psbt,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+    )?;
+
     Ok(())
 }
 
@@ -536,8 +811,8 @@ pub fn method_3(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 //     2 - Each node receives the hex PSBT, deselializes it and adds their own UTXOs (incrementally)
 //     3 - Sender get the final hex, deserializes it into the final PSBT
 //     4 - Once its done the final PSBT is circle between each node so they can sign it
-pub fn method_4(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, 5)?;
+pub fn method_4(bitcoind: &Client, args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, args)?;
 
     println!(
         "[Batch] Sender Balance: {:?}",
@@ -558,21 +833,27 @@ pub fn method_4(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     // Starting the PSBT
     println!("[Batch] Sender PSBT...");
 
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
-    let mut psbt = build_psbt(&mut sender, script_pubkey, amount, 2)?;
+    let mut psbt = build_psbt(
+        &mut sender,
+        script_pubkey,
+        amount,
+        effective_fee_rate(args.fee_rate),
+        2,
+    )?;
 
-    let fee_per_participant = Amount::from_sat(77_777);
+    let fee_per_participant = args.fee_per_participant;
     let participants = nodes.len() as u64;
 
     println!("[Batch] Getting PSBT from Network...");
     let mut psbt_hex = psbt.serialize_hex();
     for node in nodes.iter_mut() {
         println!("\n[Batch] PSBT(hex) from Network: {}\n", psbt_hex);
-        psbt_hex = add_utxos(node, psbt_hex, fee_per_participant, false)?;
+        psbt_hex = add_utxos(node, psbt_hex, args.max_utxos, fee_per_participant, false)?;
     }
 
     // DEBUG
@@ -584,9 +865,11 @@ pub fn method_4(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     // To cover fees
     psbt_hex = psbt.serialize_hex();
     println!("\n[Batch] PSBT(hex) from sender: {}\n", psbt_hex);
-    psbt_hex = add_utxos(&mut sender, psbt_hex, total_fee, true)?;
+    psbt_hex = add_utxos(&mut sender, psbt_hex, 1, total_fee, true)?;
 
     psbt = Psbt::deserialize(&hex::decode(psbt_hex)?)?;
+    let payer_output = psbt.unsigned_tx.output.len() - 1;
+    pay_network_fee(&mut psbt, payer_output, effective_fee_rate(args.fee_rate))?;
 
     println!("[Batch] Nodes signing...");
     for node in nodes.iter_mut() {
@@ -599,6 +882,22 @@ pub fn method_4(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     println!("[Batch] Extracting Tx...");
     let tx = psbt.clone().extract_tx()?;
 
+    let accounting = batch_accounting(
+        bitcoind,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+        amount,
+        paying_sender_terms(amount, fee_per_participant, participants),
+        |_, _| {
+            (
+                SignedAmount::ZERO,
+                signed(fee_per_participant),
+                NetworkFee::None,
+            )
+        },
+    )?;
+
     println!("[Batch] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
@@ -620,6 +919,15 @@ pub fn method_4(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
+    verify_batch(
+        bitcoind,
+        accounting,
+        &psbt,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+    )?;
+
     Ok(())
 }
 
@@ -627,8 +935,8 @@ pub fn method_4(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 //   Requires:
 //     1 - Sender builds a PSBT by selecting nodes' UTXOs to be added to the PSBT (via a Pool of UTXOs data)
 //     2 - Once its done the final PSBT is circle between each node so they can sign it
-pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, 5)?;
+pub fn method_5(bitcoind: &Client, args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, args)?;
 
     println!(
         "[Batch] Sender Balance: {:?}",
@@ -649,15 +957,21 @@ pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     // Starting the PSBT
     println!("[Batch] Sender PSBT...");
 
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
-    let mut psbt = build_psbt(&mut sender, script_pubkey, amount, 2)?;
+    let mut psbt = build_psbt(
+        &mut sender,
+        script_pubkey,
+        amount,
+        effective_fee_rate(args.fee_rate),
+        2,
+    )?;
 
     let participants = nodes.len() as u64;
-    let fee_per_participant = Amount::from_sat(77_777);
+    let fee_per_participant = args.fee_per_participant;
 
     println!("[Batch] Nodes send their avail txs to Network Pool...");
     let mut pool = vec![];
@@ -670,20 +984,21 @@ pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 
         let utxos = get_wallet_utxos(node);
         for utxo in utxos {
-            if let Some(canonical_tx) = node
-                .transactions()
-                .find(|tx| tx.tx_node.compute_txid() == utxo.outpoint.txid)
-            {
-                let tx = (*canonical_tx.tx_node.tx).clone();
-                nodes_utxos.push((utxo, tx));
-            }
+            let (txin, input) = psbt_input(node, &utxo)?;
+            nodes_utxos.push((utxo, txin, input));
         }
         pool.push((script_pubkey, nodes_utxos));
     }
 
     println!("[Batch] Getting transaction from Network Pool");
     for (script_buf, utxos_txs) in pool {
-        add_utxos_from_pool(&mut psbt, utxos_txs, script_buf, fee_per_participant)?;
+        add_utxos_from_pool(
+            &mut psbt,
+            utxos_txs,
+            script_buf,
+            args.max_utxos,
+            fee_per_participant,
+        )?;
     }
 
     let total_fee = fee_per_participant * participants;
@@ -691,6 +1006,8 @@ pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 
     // To cover fees
     add_utxos_to_psbt(&mut sender, &mut psbt, 2, None, total_fee, true)?;
+    let payer_output = psbt.unsigned_tx.output.len() - 1;
+    pay_network_fee(&mut psbt, payer_output, effective_fee_rate(args.fee_rate))?;
 
     for node in nodes.iter_mut() {
         node.sign(&mut psbt, SignOptions::default()).unwrap();
@@ -701,6 +1018,22 @@ pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
     println!("[Batch] Extracting Tx...");
     let tx = psbt.clone().extract_tx()?;
 
+    let accounting = batch_accounting(
+        bitcoind,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+        amount,
+        paying_sender_terms(amount, fee_per_participant, participants),
+        |_, _| {
+            (
+                SignedAmount::ZERO,
+                signed(fee_per_participant),
+                NetworkFee::None,
+            )
+        },
+    )?;
+
     println!("[Batch] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
@@ -722,6 +1055,15 @@ pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
+    verify_batch(
+        bitcoind,
+        accounting,
+        &psbt,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+    )?;
+
     Ok(())
 }
 
@@ -730,31 +1072,46 @@ pub fn method_5(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
 //     1 - Circle the origial PSBT between nodes
 //     2 - Each node adds their UTXOs to that PSBT with, at least, one output with receiver's amount (uniform)
 //     3 - Once its done the final PSBT is circle back to each node so they can sign it
-pub fn method_6(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, 5)?;
+pub fn method_6(bitcoind: &Client, args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut sender, mut receiver, mut nodes) = setup(bitcoind, args)?;
     // Starting the PSBT
     println!("[Batch] Sender PSBT...");
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
 
-    let mut psbt = build_psbt(&mut sender, script_pubkey, amount, 2)?;
+    let mut psbt = build_psbt(
+        &mut sender,
+        script_pubkey,
+        amount,
+        effective_fee_rate(args.fee_rate),
+        2,
+    )?;
 
     let participants = nodes.len() as u64;
-    let fee_per_participant = Amount::from_sat(77_777);
+    let fee_per_participant = args.fee_per_participant;
 
     let total_fee = fee_per_participant * participants;
     println!("[Batch] TotalFee    ({})", total_fee);
 
     // To cover fees
     add_utxos_to_psbt(&mut sender, &mut psbt, 2, None, total_fee, true)?;
+    let payer_output = psbt.unsigned_tx.output.len() - 1;
 
     println!("[Batch] Sending PSBT to the Network...");
     for node in nodes.iter_mut() {
-        add_utxos_to_psbt(node, &mut psbt, 2, Some(amount), fee_per_participant, false)?;
+        add_utxos_to_psbt(
+            node,
+            &mut psbt,
+            args.max_utxos,
+            Some(amount),
+            fee_per_participant,
+            false,
+        )?;
     }
+    pay_network_fee(&mut psbt, payer_output, effective_fee_rate(args.fee_rate))?;
 
     sender.sign(&mut psbt, SignOptions::default())?;
     for node in nodes.iter_mut() {
@@ -782,6 +1139,22 @@ pub fn method_6(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
         nodes_balance.push(wallet_total_balance(bitcoind, node)?);
     }
 
+    let accounting = batch_accounting(
+        bitcoind,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+        amount,
+        paying_sender_terms(amount, fee_per_participant, participants),
+        |_, _| {
+            (
+                SignedAmount::ZERO,
+                signed(fee_per_participant),
+                NetworkFee::None,
+            )
+        },
+    )?;
+
     println!("[Batch] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
@@ -815,5 +1188,14 @@ pub fn method_6(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
+    verify_batch(
+        bitcoind,
+        accounting,
+        &psbt,
+        &mut sender,
+        &mut receiver,
+        &mut nodes,
+    )?;
+
     Ok(())
 }
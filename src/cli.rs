@@ -0,0 +1,438 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use bdk_wallet::bitcoin::{Amount, FeeRate};
+use clap::{Args, Parser, Subcommand};
+use url::Url;
+
+use crate::{
+    config::config,
+    payjoin::{forwarding::Forward, sender::SenderParams},
+    wallet::ScriptType,
+};
+
+/// Research on P2PE (Payjoin) flows against a bitcoind node.
+#[derive(Parser, Debug)]
+#[command(name = "payjoin-poc", version, about, arg_required_else_help = true)]
+pub struct Cli {
+    /// Config file (defaults to $PAYJOIN_POC_CONFIG or ./payjoin-poc.toml when present)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Payjoin "directly": sender and receiver bdk wallets exchanging a PSBT
+    #[command(alias = "directly")]
+    Direct(DirectArgs),
+    /// Payjoin using rust-payjoin V1
+    V1(PayjoinArgs),
+    /// Run a BIP78 (V1) receiver endpoint
+    V1Serve(V1ServeArgs),
+    /// Payjoin using rust-payjoin V2
+    V2(PayjoinArgs),
+    /// Several V2 senders paying one receiver, merged into a single payjoin (demo only, needs
+    /// --demo-only). The merged PSBT is signed in-process by the senders' bitcoind wallets, there
+    /// is no transport for that step
+    V2Merge(V2MergeArgs),
+    /// Pending (persisted) V2 sessions
+    #[command(subcommand)]
+    V2Sessions(SessionsCommand),
+    /// Generate, decode and display BIP21 payjoin URIs
+    #[command(subcommand)]
+    Uri(UriCommand),
+    /// BIP39 seeds, encrypted under a password ($PAYJOIN_POC_SEED_PASSWORD or prompted)
+    #[command(subcommand)]
+    Seed(SeedCommand),
+    /// Payjoin batch between bdk wallets
+    Batch(BatchArgs),
+    /// Payjoin batch between ldk-node instances
+    Ldk(LdkBatchArgs),
+    /// Payjoin to open a channel between two ldk-node instances
+    LdkOpenChannel(LdkOpenChannelArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct DirectArgs {
+    /// Amount to send to the receiver (sats)
+    #[arg(long, default_value = "100000", value_parser = parse_sats)]
+    pub amount: Amount,
+    /// Fee rate of the payjoin transaction (sat/vB), defaults to the config fee rate
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub fee_rate: Option<FeeRate>,
+    /// Byte used to fill the sender's 64 bytes seed
+    #[arg(long, default_value_t = 0)]
+    pub sender_seed: u8,
+    /// Byte used to fill the receiver's 64 bytes seed
+    #[arg(long, default_value_t = 1)]
+    pub receiver_seed: u8,
+    /// Stored seed of the sender (see `seed`), instead of the --sender-seed test seed
+    #[arg(long)]
+    pub sender_seed_name: Option<String>,
+    /// Stored seed of the receiver (see `seed`), instead of the --receiver-seed test seed
+    #[arg(long)]
+    pub receiver_seed_name: Option<String>,
+    /// Script type of the sender's wallet
+    #[arg(long, value_enum, default_value_t = ScriptType::P2wpkh)]
+    pub sender_script_type: ScriptType,
+    /// Script type of the receiver's wallet
+    #[arg(long, value_enum, default_value_t = ScriptType::P2wpkh)]
+    pub receiver_script_type: ScriptType,
+    /// Max UTXOs the sender adds to the PSBT
+    #[arg(long, default_value_t = 3)]
+    pub sender_max_utxos: u16,
+    /// Max UTXOs the receiver adds to the PSBT
+    #[arg(long, default_value_t = 2)]
+    pub receiver_max_utxos: u16,
+}
+
+#[derive(Args, Debug)]
+pub struct PayjoinArgs {
+    /// Amount to send to the receiver (sats)
+    #[arg(long, default_value = "100000", value_parser = parse_sats)]
+    pub amount: Amount,
+    /// Fee rate of the sender's original PSBT (sat/vB), defaults to the config fee rate
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub fee_rate: Option<FeeRate>,
+    /// bitcoind wallet used by the sender, defaults to the config one
+    #[arg(long)]
+    pub sender_wallet: Option<String>,
+    /// bitcoind wallet used by the receiver, defaults to the config one
+    #[arg(long)]
+    pub receiver_wallet: Option<String>,
+    /// Creditor the receiver pays out of the payjoin (`<address>:<sats>`, repeatable), its output
+    /// becomes these plus a drain output with the rest
+    #[arg(long = "forward")]
+    pub forwards: Vec<Forward>,
+    #[command(flatten)]
+    pub sender_params: SenderArgs,
+}
+
+/// BIP78 optional parameters of the sender.
+#[derive(Args, Debug)]
+pub struct SenderArgs {
+    /// Max fee the receiver may take from the sender's change (sats), defaults to 10000 for v1
+    /// and 1 for v2
+    #[arg(long, value_parser = parse_sats)]
+    pub max_additional_fee: Option<Amount>,
+    /// Output the additional fee is taken from, defaults to the change output
+    #[arg(long)]
+    pub additional_fee_output_index: Option<usize>,
+    /// Min fee rate the payjoin must pay (sat/vB)
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub min_fee_rate: Option<FeeRate>,
+    /// Ask the receiver to keep its output as in the original PSBT
+    #[arg(long)]
+    pub disable_output_substitution: bool,
+    /// Lower the fee contribution to the change value instead of failing (always on for v2)
+    #[arg(long)]
+    pub clamp_fee_contribution: bool,
+}
+
+impl SenderArgs {
+    /// Parameters of a v1 sender: 10000 sats of max fee contribution, not clamped.
+    pub fn params_v1(&self) -> SenderParams {
+        self.params(Amount::from_sat(10_000), self.clamp_fee_contribution)
+    }
+
+    /// Parameters of a v2 sender: 1 sat of max fee contribution, clamped to the change.
+    pub fn params_v2(&self) -> SenderParams {
+        self.params(Amount::from_sat(1), true)
+    }
+
+    fn params(&self, max_additional_fee: Amount, clamp_fee_contribution: bool) -> SenderParams {
+        SenderParams {
+            max_additional_fee: self.max_additional_fee.unwrap_or(max_additional_fee),
+            additional_fee_output_index: self.additional_fee_output_index,
+            min_fee_rate: self.min_fee_rate.unwrap_or(FeeRate::ZERO),
+            disable_output_substitution: self.disable_output_substitution,
+            clamp_fee_contribution,
+        }
+    }
+}
+
+impl PayjoinArgs {
+    pub fn sender_wallet_name(&self) -> &str {
+        self.sender_wallet
+            .as_deref()
+            .unwrap_or(&config().sender_wallet)
+    }
+
+    pub fn receiver_wallet_name(&self) -> &str {
+        self.receiver_wallet
+            .as_deref()
+            .unwrap_or(&config().receiver_wallet)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct V2MergeArgs {
+    /// Amount each sender pays to the receiver (sats)
+    #[arg(long, default_value = "100000", value_parser = parse_sats)]
+    pub amount: Amount,
+    /// Fee rate of the senders' original PSBTs (sat/vB), defaults to the config fee rate
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub fee_rate: Option<FeeRate>,
+    /// bitcoind wallets of the senders (at least 2)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "sender-1,sender-2,sender-3"
+    )]
+    pub senders: Vec<String>,
+    /// bitcoind wallet used by the receiver, defaults to the config one
+    #[arg(long)]
+    pub receiver_wallet: Option<String>,
+    /// How long the receiver waits for the original PSBTs before merging (secs)
+    #[arg(long, default_value_t = 30)]
+    pub window_secs: u64,
+    /// Sender wallet refusing to sign the merged PSBT, to exercise the fallback
+    #[arg(long)]
+    pub drop_out: Option<String>,
+    /// Acknowledges that the receiver holds every sender's bitcoind wallet: the merged PSBT is
+    /// signed in-process, remote senders can't take part
+    #[arg(long)]
+    pub demo_only: bool,
+    #[command(flatten)]
+    pub sender_params: SenderArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct V1ServeArgs {
+    /// Address the endpoint listens on
+    #[arg(long, default_value = "127.0.0.1:3000")]
+    pub bind: SocketAddr,
+    /// Endpoint URL put in the payjoin URI (e.g. `https://pay.example.com/payjoin`), required when
+    /// listening on all interfaces (0.0.0.0 or ::)
+    #[arg(long)]
+    pub public_url: Option<Url>,
+    /// bitcoind wallet used by the receiver, defaults to the config one
+    #[arg(long)]
+    pub receiver_wallet: Option<String>,
+    /// Amount requested in the printed payjoin URI (sats)
+    #[arg(long, value_parser = parse_sats)]
+    pub amount: Option<Amount>,
+    /// Creditor paid out of the payjoin of the printed URI (`<address>:<sats>`, repeatable)
+    #[arg(long = "forward")]
+    pub forwards: Vec<Forward>,
+    /// PEM certificate for TLS
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for TLS
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Serve TLS with a self-signed certificate for localhost (testing only)
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub self_signed: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionsCommand {
+    /// List the pending sender and receiver sessions
+    List,
+    /// Resume a session where it was left
+    Resume {
+        /// Session id, see `v2-sessions list`
+        id: String,
+    },
+    /// Drop a session
+    Cancel {
+        /// Session id, see `v2-sessions list`
+        id: String,
+        /// Sender sessions only: broadcast the original tx so the payment still goes out
+        #[arg(long)]
+        broadcast_original: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UriCommand {
+    /// Build a payjoin URI for an address and `pj=` endpoint
+    Generate(UriGenerateArgs),
+    /// Decode a payjoin URI and check it is valid for the configured network
+    Parse {
+        uri: String,
+        /// Also print it as a QR code
+        #[arg(long)]
+        qr: bool,
+    },
+    /// Print a URI (or any text) as a QR code
+    Qr {
+        data: String,
+        /// Plain `#` characters instead of Unicode half blocks
+        #[arg(long)]
+        ascii: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SeedCommand {
+    /// Generate a mnemonic, store it and print it once to be written down
+    Generate {
+        name: String,
+        /// 12, 15, 18, 21 or 24
+        #[arg(long, default_value_t = 24)]
+        words: usize,
+        /// Protect it with a BIP39 passphrase too (prompted)
+        #[arg(long)]
+        passphrase: bool,
+    },
+    /// Store an existing mnemonic (prompted)
+    Import {
+        name: String,
+        /// It has a BIP39 passphrase (prompted)
+        #[arg(long)]
+        passphrase: bool,
+    },
+    /// Print the mnemonic (and passphrase) of a stored seed
+    Show { name: String },
+    /// List the stored seeds
+    List,
+}
+
+#[derive(Args, Debug)]
+pub struct UriGenerateArgs {
+    /// Receiver's address, for the configured network
+    #[arg(long)]
+    pub address: String,
+    /// BIP78 endpoint, or v2 directory subdirectory URL with its `#RK1..+OH1..+EX1..` fragment
+    #[arg(long)]
+    pub pj: Url,
+    /// Amount requested (sats)
+    #[arg(long, value_parser = parse_sats)]
+    pub amount: Option<Amount>,
+    #[arg(long)]
+    pub label: Option<String>,
+    #[arg(long)]
+    pub message: Option<String>,
+    /// Add `pjos=0`: the sender must keep the receiver's output as is
+    #[arg(long)]
+    pub disable_output_substitution: bool,
+    /// Also print it as a QR code
+    #[arg(long)]
+    pub qr: bool,
+    /// Plain `#` characters for the QR code instead of Unicode half blocks
+    #[arg(long, requires = "qr")]
+    pub ascii: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    /// Batch method to run (1-6)
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=6))]
+    pub method: u8,
+    /// Amount the sender pays to the receiver (sats)
+    #[arg(long, default_value = "777777", value_parser = parse_sats)]
+    pub amount: Amount,
+    /// Fee rate of the sender's PSBT (sat/vB), defaults to the config fee rate
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub fee_rate: Option<FeeRate>,
+    /// Number of participants joining the batch
+    #[arg(long, default_value_t = 5)]
+    pub participants: u8,
+    /// Max UTXOs each participant adds to the PSBT
+    #[arg(long, default_value_t = 2)]
+    pub max_utxos: u16,
+    /// Fee the sender pays to each participant (sats)
+    #[arg(long, default_value = "77777", value_parser = parse_sats)]
+    pub fee_per_participant: Amount,
+    /// Byte used to fill the sender's 64 bytes seed
+    #[arg(long, default_value_t = 0)]
+    pub sender_seed: u8,
+    /// Byte used to fill the receiver's 64 bytes seed, must not be a node's (1 to --participants)
+    #[arg(long, default_value_t = 255)]
+    pub receiver_seed: u8,
+    /// Stored seed of the sender (see `seed`), instead of the --sender-seed test seed
+    #[arg(long)]
+    pub sender_seed_name: Option<String>,
+    /// Stored seed of the receiver (see `seed`), instead of the --receiver-seed test seed
+    #[arg(long)]
+    pub receiver_seed_name: Option<String>,
+    /// Script type of the sender's wallet
+    #[arg(long, value_enum, default_value_t = ScriptType::P2wpkh)]
+    pub sender_script_type: ScriptType,
+    /// Script type of the receiver's wallet
+    #[arg(long, value_enum, default_value_t = ScriptType::P2wpkh)]
+    pub receiver_script_type: ScriptType,
+    /// Receiver's wallet from this descriptor instead of the seed (e.g. a watch-only `tr(tpub...)`),
+    /// `@<file>` or `-` to be prompted for one with private keys
+    #[arg(long, conflicts_with = "receiver_script_type")]
+    pub receiver_descriptor: Option<String>,
+    /// Nodes cycle through every script type instead of all being P2WPKH
+    #[arg(long)]
+    pub mixed_script_types: bool,
+    /// Stored seeds of the nodes: `<prefix>-<idx>`, generated the first time. Test seeds (the
+    /// node index repeated) without it
+    #[arg(long)]
+    pub node_seed_prefix: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct LdkBatchArgs {
+    /// Amount the sender node pays to the receiver (sats)
+    #[arg(long, default_value = "777777", value_parser = parse_sats)]
+    pub amount: Amount,
+    /// Fee rate of the batch PSBT (sat/vB), defaults to the config fee rate
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub fee_rate: Option<FeeRate>,
+    /// Max number of nodes joining the batch
+    #[arg(long, default_value_t = 6)]
+    pub participants: u8,
+    /// Max UTXOs each node adds to the PSBT
+    #[arg(long, default_value_t = 4)]
+    pub max_utxos: u8,
+    /// Fee the sender node pays to each participant (sats)
+    #[arg(long, default_value = "99999", value_parser = parse_sats)]
+    pub fee_per_participant: Amount,
+    /// Byte used to fill the receiver's 64 bytes seed
+    #[arg(long, default_value_t = 255)]
+    pub receiver_seed: u8,
+    /// Stored seed of the receiver (see `seed`), instead of the --receiver-seed test seed
+    #[arg(long)]
+    pub receiver_seed_name: Option<String>,
+    /// Stored seeds of the nodes: `<prefix>-<idx>`, generated the first time. Test seeds (the
+    /// node index repeated) without it
+    #[arg(long)]
+    pub node_seed_prefix: Option<String>,
+    /// Script type of the receiver's wallet
+    #[arg(long, value_enum, default_value_t = ScriptType::P2wpkh)]
+    pub receiver_script_type: ScriptType,
+    /// Receiver's wallet from this descriptor instead of the seed (e.g. a watch-only `tr(tpub...)`),
+    /// `@<file>` or `-` to be prompted for one with private keys
+    #[arg(long, conflicts_with = "receiver_script_type")]
+    pub receiver_descriptor: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct LdkOpenChannelArgs {
+    /// Channel capacity (sats)
+    #[arg(long, default_value = "777777", value_parser = parse_sats)]
+    pub amount: Amount,
+    /// Fee rate of the funding PSBT (sat/vB), defaults to the config fee rate
+    #[arg(long, value_parser = parse_fee_rate)]
+    pub fee_rate: Option<FeeRate>,
+    /// Stored seeds of the nodes: `<prefix>-<idx>`, generated the first time. Test seeds (the
+    /// node index repeated) without it
+    #[arg(long)]
+    pub node_seed_prefix: Option<String>,
+}
+
+/// Fee rate given on the command line or the config default one.
+pub fn effective_fee_rate(fee_rate: Option<FeeRate>) -> FeeRate {
+    fee_rate.unwrap_or(config().fee_rate)
+}
+
+fn parse_sats(value: &str) -> Result<Amount, String> {
+    value
+        .parse::<u64>()
+        .map(Amount::from_sat)
+        .map_err(|e| format!("invalid amount (sats): {}", e))
+}
+
+fn parse_fee_rate(value: &str) -> Result<FeeRate, String> {
+    let sat_per_vb = value
+        .parse::<u64>()
+        .map_err(|e| format!("invalid fee rate (sat/vB): {}", e))?;
+    FeeRate::from_sat_per_vb(sat_per_vb).ok_or_else(|| "fee rate overflow".to_string())
+}
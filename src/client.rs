@@ -1,18 +1,19 @@
 use std::{thread::sleep, time::Duration};
 
-use bdk_wallet::bitcoin::Amount;
-use bitcoincore_rpc::{Auth, Client, RpcApi};
+use bdk_wallet::bitcoin::{Amount, Network};
+use bitcoincore_rpc::{Client, RpcApi};
+
+use crate::config::config;
 
 pub fn bitcoind_client(wallet: &str) -> Result<Client, bitcoincore_rpc::Error> {
-    let auth = Auth::UserPass("local".to_string(), "local".to_string());
-    let mut bitcoind = Client::new("http://0.0.0.0:38332", auth.clone())?;
+    let config = config();
+    let rpc_url = config.rpc_url.as_str().trim_end_matches('/');
+    let auth = config.rpc_auth.to_auth();
+    let mut bitcoind = Client::new(rpc_url, auth.clone())?;
     let _ = bitcoind
         .create_wallet(wallet, None, None, None, None)
         .map_err(|_| println!("ERROR(create_wallet)"));
-    bitcoind = Client::new(
-        format!("http://0.0.0.0:38332/wallet/{}", wallet).as_str(),
-        auth,
-    )?;
+    bitcoind = Client::new(format!("{}/wallet/{}", rpc_url, wallet).as_str(), auth)?;
     Ok(bitcoind)
 }
 
@@ -35,7 +36,49 @@ pub fn get_client_balance(bitcoind: &Client) -> Result<Amount, Box<dyn std::erro
     Ok(total_balance)
 }
 
+// Coinbase outputs need 100 confirmations before they can be spent
+const COINBASE_MATURITY: u64 = 100;
+
+/// On regtest we are the miner, make sure the miner wallet has mature coins to fund the flows.
+pub fn fund_miner(
+    bitcoind: &Client,
+    min_balance: Amount,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if config().network != Network::Regtest {
+        return Ok(());
+    }
+    let mut balance = bitcoind.get_balances()?.mine.trusted;
+    while balance < min_balance {
+        let blocks = if bitcoind.get_block_count()? < COINBASE_MATURITY {
+            COINBASE_MATURITY + 1
+        } else {
+            1
+        };
+        mine_blocks(bitcoind, blocks)?;
+        balance = bitcoind.get_balances()?.mine.trusted;
+    }
+    println!("    -> Miner balance: {}", balance);
+    Ok(())
+}
+
+/// Regtest only: mine `blocks` to an address of the `bitcoind` (miner) wallet.
+pub fn mine_blocks(bitcoind: &Client, blocks: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let address = bitcoind.get_new_address(None, None)?.assume_checked();
+    bitcoind.generate_to_address(blocks, &address)?;
+    println!(
+        "    -> Mined {:?} block(s) [tip={:?}]",
+        blocks,
+        bitcoind.get_block_count()?
+    );
+    Ok(())
+}
+
+/// Waits until `blocks` new blocks are found. On regtest they are mined right away by the
+/// `bitcoind` wallet (the miner), on other networks we poll for an external miner.
 pub fn wait_for_block(bitcoind: &Client, blocks: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if config().network == Network::Regtest {
+        return mine_blocks(bitcoind, blocks);
+    }
     let initial_block = bitcoind.get_block_count()?;
     let target_block = initial_block + blocks;
     loop {
@@ -48,3 +91,16 @@ pub fn wait_for_block(bitcoind: &Client, blocks: u64) -> Result<(), Box<dyn std:
     }
     Ok(())
 }
+
+/// Gives the LDK nodes time to see funds just sent to them: on regtest a block is mined so they
+/// confirm right away, on other networks we only sleep `delay` (a block would take ~10 minutes).
+pub fn wait_for_funds(
+    bitcoind: &Client,
+    delay: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if config().network == Network::Regtest {
+        return mine_blocks(bitcoind, 1);
+    }
+    sleep(delay);
+    Ok(())
+}
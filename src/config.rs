@@ -0,0 +1,418 @@
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use bdk_wallet::bitcoin::{FeeRate, Network};
+use bitcoincore_rpc::Auth;
+use serde::Deserialize;
+use url::Url;
+
+const DEFAULT_CONFIG_FILE: &str = "payjoin-poc.toml";
+const ENV_PREFIX: &str = "PAYJOIN_POC_";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub enum RpcAuth {
+    CookieFile(PathBuf),
+    UserPass(String, String),
+}
+
+impl RpcAuth {
+    pub fn to_auth(&self) -> Auth {
+        match self {
+            RpcAuth::CookieFile(path) => Auth::CookieFile(path.clone()),
+            RpcAuth::UserPass(user, pass) => Auth::UserPass(user.clone(), pass.clone()),
+        }
+    }
+
+    /// (user, pass) pair, reading it from the cookie file if that is what we were given.
+    pub fn credentials(&self) -> Result<(String, String), Box<dyn std::error::Error>> {
+        match self {
+            RpcAuth::CookieFile(path) => {
+                let cookie = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read cookie file {:?}: {}", path, e))?;
+                let (user, pass) = cookie
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid cookie file {:?}", path))?;
+                Ok((user.to_string(), pass.to_string()))
+            }
+            RpcAuth::UserPass(user, pass) => Ok((user.clone(), pass.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub rpc_url: Url,
+    pub rpc_auth: RpcAuth,
+    pub network: Network,
+    pub data_dir: PathBuf,
+    /// Default fee rate used by every flow when none is given on the command line
+    pub fee_rate: FeeRate,
+    pub miner_wallet: String,
+    pub sender_wallet: String,
+    pub receiver_wallet: String,
+    /// Where the bdk wallets get their blocks from
+    pub chain_source: ChainSource,
+    /// How long a payjoin sender waits for the receiver's proposal before broadcasting the original tx
+    pub sender_timeout: Duration,
+    /// How long a payjoin receiver waits for the payjoin (or a conflict) before broadcasting the original tx
+    pub fallback_delay: Duration,
+    pub receiver_policy: ReceiverPolicy,
+    /// BIP77 directory and OHTTP relays, local stand-ins are started when not set
+    pub v2_directory: Option<Url>,
+    pub v2_ohttp_relays: Vec<Url>,
+    pub tls: TlsTrust,
+}
+
+/// How the bdk wallets sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainSource {
+    /// Every block over RPC (`getblock`)
+    Rpc,
+    /// BIP158 filters (`getblockfilter`, needs `-blockfilterindex=1`), only matching blocks are
+    /// downloaded
+    Filters,
+}
+
+impl FromStr for ChainSource {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<ChainSource, String> {
+        match value {
+            "rpc" => Ok(ChainSource::Rpc),
+            "filters" => Ok(ChainSource::Filters),
+            other => Err(format!("expected rpc or filters, got {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for ChainSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChainSource::Rpc => "rpc",
+            ChainSource::Filters => "filters",
+        })
+    }
+}
+
+/// Certificates the HTTPS clients (v1 sender, OHTTP relay/directory) trust.
+#[derive(Debug, Clone, Default)]
+pub struct TlsTrust {
+    /// PEM bundle trusted on top of the system roots
+    pub ca_bundle: Option<PathBuf>,
+    /// When set, only these PEM certificates are trusted (no system roots). They are trust
+    /// anchors, not pins: any certificate they issued is accepted too
+    pub trusted_roots: Vec<PathBuf>,
+}
+
+/// What a payjoin receiver (v1 and v2) accepts and contributes.
+#[derive(Debug, Clone)]
+pub struct ReceiverPolicy {
+    /// Original PSBTs paying less than it are rejected
+    pub min_original_fee_rate: Option<FeeRate>,
+    /// Min fee rate of the receiver's proposal, the minimum relay fee rate when unset
+    pub min_proposal_fee_rate: Option<FeeRate>,
+    /// Max effective fee rate the receiver pays for the inputs it contributes
+    pub max_fee_rate: FeeRate,
+    /// Inputs the receiver contributes, a single one is picked avoiding the UIH heuristics
+    pub max_inputs: usize,
+    /// When the original PSBT pays at most this fee rate, contribute up to
+    /// `consolidation_max_inputs` (smallest first) to consolidate UTXOs while fees are low
+    pub consolidation_fee_rate: Option<FeeRate>,
+    pub consolidation_max_inputs: usize,
+    /// Replace the receiver output with a fresh address (or custom outputs)
+    pub allow_output_substitution: bool,
+}
+
+impl Config {
+    /// Host and port of the RPC endpoint, as ldk-node wants them.
+    pub fn rpc_host_port(&self) -> Result<(String, u16), Box<dyn std::error::Error>> {
+        let host = self
+            .rpc_url
+            .host_str()
+            .ok_or_else(|| format!("Missing host in rpc url: {}", self.rpc_url))?;
+        let port = self
+            .rpc_url
+            .port_or_known_default()
+            .ok_or_else(|| format!("Missing port in rpc url: {}", self.rpc_url))?;
+        Ok((host.to_string(), port))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    network: Option<String>,
+    data_dir: Option<PathBuf>,
+    rpc: RpcSection,
+    fees: FeesSection,
+    wallets: WalletsSection,
+    sender: SenderSection,
+    receiver: ReceiverSection,
+    v2: V2Section,
+    tls: TlsSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RpcSection {
+    url: Option<String>,
+    user: Option<String>,
+    pass: Option<String>,
+    cookie_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FeesSection {
+    /// sat/vB
+    fee_rate: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct WalletsSection {
+    miner: Option<String>,
+    sender: Option<String>,
+    receiver: Option<String>,
+    chain_source: Option<ChainSource>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SenderSection {
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReceiverSection {
+    fallback_delay_secs: Option<u64>,
+    /// sat/vB
+    min_original_fee_rate: Option<u64>,
+    /// sat/vB
+    min_proposal_fee_rate: Option<u64>,
+    /// sat/vB
+    max_fee_rate: Option<u64>,
+    max_inputs: Option<usize>,
+    /// sat/vB
+    consolidation_fee_rate: Option<u64>,
+    consolidation_max_inputs: Option<usize>,
+    allow_output_substitution: Option<bool>,
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(format!("{}{}", ENV_PREFIX, name))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct V2Section {
+    directory: Option<Url>,
+    ohttp_relays: Vec<Url>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    ca_bundle: Option<PathBuf>,
+    trusted_roots: Vec<PathBuf>,
+    /// Former name of `trusted_roots`, refused: they were never checked as pins
+    pinned_certs: Option<Vec<PathBuf>>,
+}
+
+/// `PAYJOIN_POC_<name>` parsed as `T` if set, the config file value otherwise.
+fn env_or<T>(name: &str, file_value: Option<T>) -> Result<Option<T>, Box<dyn std::error::Error>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env_var(name) {
+        Some(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| format!("Invalid {}{} {}: {}", ENV_PREFIX, name, value, e).into()),
+        None => Ok(file_value),
+    }
+}
+
+fn sat_per_vb(value: u64) -> Result<FeeRate, Box<dyn std::error::Error>> {
+    Ok(FeeRate::from_sat_per_vb(value).ok_or_else(|| format!("Fee rate overflow: {}", value))?)
+}
+
+fn read_file(path: Option<&Path>) -> Result<ConfigFile, Box<dyn std::error::Error>> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match env_var("CONFIG") {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        },
+    };
+    if !path.exists() {
+        if required {
+            return Err(format!("Config file not found: {:?}", path).into());
+        }
+        return Ok(ConfigFile::default());
+    }
+    let content = fs::read_to_string(&path)?;
+    let file = toml::from_str(&content)
+        .map_err(|e| format!("Failed to parse config file {:?}: {}", path, e))?;
+    Ok(file)
+}
+
+/// Layers: defaults < config file < PAYJOIN_POC_* env vars.
+fn build(file: ConfigFile) -> Result<Config, Box<dyn std::error::Error>> {
+    let network = env_var("NETWORK")
+        .or(file.network)
+        .unwrap_or_else(|| "signet".to_string());
+    let network =
+        Network::from_str(&network).map_err(|e| format!("Invalid network {}: {}", network, e))?;
+
+    let rpc_url = env_var("RPC_URL")
+        .or(file.rpc.url)
+        .unwrap_or_else(|| "http://0.0.0.0:38332".to_string());
+    let rpc_url =
+        Url::parse(&rpc_url).map_err(|e| format!("Invalid rpc url {}: {}", rpc_url, e))?;
+
+    let cookie_file = env_var("RPC_COOKIE_FILE")
+        .map(PathBuf::from)
+        .or(file.rpc.cookie_file);
+    let rpc_auth = match cookie_file {
+        Some(path) => RpcAuth::CookieFile(path),
+        None => RpcAuth::UserPass(
+            env_var("RPC_USER")
+                .or(file.rpc.user)
+                .unwrap_or_else(|| "local".to_string()),
+            env_var("RPC_PASS")
+                .or(file.rpc.pass)
+                .unwrap_or_else(|| "local".to_string()),
+        ),
+    };
+
+    let data_dir = env_var("DATA_DIR")
+        .map(PathBuf::from)
+        .or(file.data_dir)
+        .unwrap_or_else(|| PathBuf::from("data"));
+
+    // Every flow defaults to 1 sat/vB (v2 had its own 10 sat/vB before), see the README
+    let fee_rate = sat_per_vb(env_or("FEE_RATE", file.fees.fee_rate)?.unwrap_or(1))?;
+    let sender_timeout = env_or("SENDER_TIMEOUT_SECS", file.sender.timeout_secs)?.unwrap_or(30);
+
+    let receiver = file.receiver;
+    let fallback_delay =
+        env_or("RECEIVER_FALLBACK_DELAY_SECS", receiver.fallback_delay_secs)?.unwrap_or(120);
+    let receiver_policy = ReceiverPolicy {
+        min_original_fee_rate: env_or(
+            "RECEIVER_MIN_ORIGINAL_FEE_RATE",
+            receiver.min_original_fee_rate,
+        )?
+        .map(sat_per_vb)
+        .transpose()?,
+        min_proposal_fee_rate: env_or(
+            "RECEIVER_MIN_PROPOSAL_FEE_RATE",
+            receiver.min_proposal_fee_rate,
+        )?
+        .map(sat_per_vb)
+        .transpose()?,
+        max_fee_rate: sat_per_vb(
+            env_or("RECEIVER_MAX_FEE_RATE", receiver.max_fee_rate)?.unwrap_or(2),
+        )?,
+        max_inputs: env_or("RECEIVER_MAX_INPUTS", receiver.max_inputs)?.unwrap_or(1),
+        consolidation_fee_rate: env_or(
+            "RECEIVER_CONSOLIDATION_FEE_RATE",
+            receiver.consolidation_fee_rate,
+        )?
+        .map(sat_per_vb)
+        .transpose()?,
+        consolidation_max_inputs: env_or(
+            "RECEIVER_CONSOLIDATION_MAX_INPUTS",
+            receiver.consolidation_max_inputs,
+        )?
+        .unwrap_or(5),
+        allow_output_substitution: env_or(
+            "RECEIVER_ALLOW_OUTPUT_SUBSTITUTION",
+            receiver.allow_output_substitution,
+        )?
+        .unwrap_or(true),
+    };
+    if receiver_policy.max_inputs == 0 {
+        return Err("receiver max_inputs must be at least 1".into());
+    }
+
+    let v2_directory = env_or("V2_DIRECTORY", file.v2.directory)?;
+    let v2_ohttp_relays = match env_var("V2_OHTTP_RELAYS") {
+        Some(urls) => urls
+            .split(',')
+            .map(|url| Url::parse(url).map_err(|e| format!("Invalid relay url {}: {}", url, e)))
+            .collect::<Result<_, _>>()?,
+        None => file.v2.ohttp_relays,
+    };
+    if v2_directory.is_some() == v2_ohttp_relays.is_empty() {
+        return Err("v2 directory and ohttp_relays must be set together".into());
+    }
+
+    if file.tls.pinned_certs.is_some() || env_var("TLS_PINNED_CERTS").is_some() {
+        return Err(
+            "[tls] pinned_certs is not supported: certificates are checked as trust roots, \
+             not pins (any certificate they issued passes). List them under trusted_roots if \
+             that is what you want"
+                .into(),
+        );
+    }
+    let tls = TlsTrust {
+        ca_bundle: env_or("TLS_CA_BUNDLE", file.tls.ca_bundle)?,
+        trusted_roots: match env_var("TLS_TRUSTED_ROOTS") {
+            Some(paths) => paths.split(',').map(PathBuf::from).collect(),
+            None => file.tls.trusted_roots,
+        },
+    };
+
+    Ok(Config {
+        rpc_url,
+        rpc_auth,
+        network,
+        data_dir,
+        fee_rate,
+        miner_wallet: env_var("MINER_WALLET")
+            .or(file.wallets.miner)
+            .unwrap_or_else(|| "miner".to_string()),
+        sender_wallet: env_var("SENDER_WALLET")
+            .or(file.wallets.sender)
+            .unwrap_or_else(|| "sender".to_string()),
+        receiver_wallet: env_var("RECEIVER_WALLET")
+            .or(file.wallets.receiver)
+            .unwrap_or_else(|| "receiver".to_string()),
+        chain_source: env_or("CHAIN_SOURCE", file.wallets.chain_source)?
+            .unwrap_or(ChainSource::Rpc),
+        sender_timeout: Duration::from_secs(sender_timeout),
+        fallback_delay: Duration::from_secs(fallback_delay),
+        receiver_policy,
+        v2_directory,
+        v2_ohttp_relays,
+        tls,
+    })
+}
+
+/// Loads the configuration once, every module reads it through `config()` afterwards.
+pub fn init(path: Option<&Path>) -> Result<&'static Config, Box<dyn std::error::Error>> {
+    let config = build(read_file(path)?)?;
+    println!(
+        "[Config] network={} | rpc={} | data_dir={:?}",
+        config.network, config.rpc_url, config.data_dir
+    );
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("config::init() must be called first")
+}
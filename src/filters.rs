@@ -0,0 +1,190 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use bdk_wallet::{
+    bitcoin::{bip158::BlockFilter, block::Header, Block, BlockHash, ScriptBuf, Transaction, Txid},
+    chain::BlockId,
+    Wallet,
+};
+use bitcoincore_rpc::{Client, RpcApi};
+
+use crate::wallet::SYNC_PROGRESS_INTERVAL;
+
+/// Best chain fetched in this run, by height: hash, header and filter of each block, shared by
+/// every wallet syncing through them. Always a linked chain, from its lowest block to the tip.
+static FILTERS: Mutex<Option<BTreeMap<u32, (BlockHash, Header, BlockFilter)>>> = Mutex::new(None);
+
+/// Mempool txs fetched in this run and when they entered the mempool.
+static MEMPOOL: Mutex<Option<HashMap<Txid, (Transaction, u64)>>> = Mutex::new(None);
+
+/// Compact block filter (BIP157/158) sync: only the blocks whose filter matches one of the
+/// wallet's scripts (revealed and lookahead) are downloaded, the others just move the
+/// checkpoint. Needs bitcoind with `-blockfilterindex=1`.
+pub fn sync_with_filters(
+    client: &Client,
+    wallet: &mut Wallet,
+    latest: u32,
+    debug: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stored = wallet.latest_checkpoint().height();
+    let mut connected_to = find_fork(client, wallet)?;
+    if connected_to.height < stored {
+        println!(
+            "    -> WalletSyncFilters: Reorg! Rolling back to {} (tip was {})",
+            connected_to.height, stored
+        );
+    }
+
+    let mut scripts = wallet_scripts(wallet);
+    let mut scanned = 0;
+    let mut downloaded = 0;
+    let mut height = connected_to.height + 1;
+    fetch_filters(client, height, latest)?;
+    while height <= latest {
+        let (hash, header, filter) = cached_filter(height)?;
+        if header.prev_blockhash != connected_to.hash {
+            // The chain changed while we were scanning it, start again from where we agree
+            connected_to = find_fork(client, wallet)?;
+            fetch_filters(client, connected_to.height + 1, latest)?;
+            println!(
+                "    -> WalletSyncFilters: Reorg! Rolling back to {}",
+                connected_to.height
+            );
+            height = connected_to.height + 1;
+            continue;
+        }
+
+        let matched = filter.match_any(&hash, scripts.iter().map(|script| script.as_bytes()))?;
+        let block = if matched {
+            downloaded += 1;
+            client.get_block(&hash)?
+        } else {
+            // No tx of ours: the header is enough to extend the wallet's chain
+            Block {
+                header,
+                txdata: vec![],
+            }
+        };
+        wallet.apply_block_connected_to(&block, height, connected_to)?;
+        if matched {
+            // Receiving may have revealed new addresses, and grown the lookahead
+            scripts = wallet_scripts(wallet);
+        }
+
+        connected_to = BlockId { height, hash };
+        scanned += 1;
+        if debug && (scanned % SYNC_PROGRESS_INTERVAL == 0 || height == latest) {
+            println!(
+                "    -> WalletSyncFilters: {}/{} ({} filters | {} blocks downloaded)",
+                height, latest, scanned, downloaded
+            );
+        }
+        height += 1;
+    }
+    Ok(())
+}
+
+/// Last checkpoint of the wallet still in the best chain.
+fn find_fork(client: &Client, wallet: &Wallet) -> Result<BlockId, Box<dyn std::error::Error>> {
+    for checkpoint in wallet.latest_checkpoint().iter() {
+        let best = client.get_block_hash(checkpoint.height() as u64).ok();
+        if best == Some(checkpoint.hash()) {
+            return Ok(checkpoint.block_id());
+        }
+    }
+    Err("Wallet doesn't share a block with the node, wrong network?".into())
+}
+
+fn wallet_scripts(wallet: &Wallet) -> Vec<ScriptBuf> {
+    wallet
+        .spk_index()
+        .inner()
+        .all_spks()
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Makes the cache cover `from..=latest` of the best chain: walks down from the tip until it
+/// joins the cached blocks, so each header and filter is fetched once per run whatever the number
+/// of wallets, and a wallet already up to date costs a single `getblockhash`.
+fn fetch_filters(
+    client: &Client,
+    from: u32,
+    latest: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if from > latest {
+        return Ok(());
+    }
+    let mut cache = FILTERS.lock().map_err(|e| e.to_string())?;
+    let cache = cache.get_or_insert_with(BTreeMap::new);
+    // Blocks above the tip were reorged out
+    cache.split_off(&(latest + 1));
+
+    let mut height = latest;
+    let mut hash = client.get_block_hash(latest as u64)?;
+    loop {
+        if cache.get(&height).map(|(cached, _, _)| *cached) == Some(hash) {
+            // Joined the cached chain, only what lies below it may be missing
+            let Some((&bottom, (_, header, _))) = cache.first_key_value() else {
+                break;
+            };
+            if bottom <= from {
+                break;
+            }
+            hash = header.prev_blockhash;
+            height = bottom - 1;
+            continue;
+        }
+
+        let filter = client.get_block_filter(&hash).map_err(|e| {
+            format!(
+                "getblockfilter {} failed, is bitcoind running with -blockfilterindex=1? {}",
+                hash, e
+            )
+        })?;
+        let header = client.get_block_header(&hash)?;
+        let prev = header.prev_blockhash;
+        cache.insert(height, (hash, header, BlockFilter::new(&filter.filter)));
+        if height <= from {
+            // Whatever is cached below belongs to another chain
+            *cache = cache.split_off(&height);
+            break;
+        }
+        hash = prev;
+        height -= 1;
+    }
+    Ok(())
+}
+
+fn cached_filter(
+    height: u32,
+) -> Result<(BlockHash, Header, BlockFilter), Box<dyn std::error::Error>> {
+    let cache = FILTERS.lock().map_err(|e| e.to_string())?;
+    cache
+        .as_ref()
+        .and_then(|cache| cache.get(&height))
+        .cloned()
+        .ok_or_else(|| format!("No filter fetched for block {}", height).into())
+}
+
+/// Mempool txs with the time they were first seen, shared by every wallet: one `getrawmempool`
+/// per sync and a `getrawtransaction` for the txs not fetched yet.
+pub fn mempool(client: &Client) -> Result<Vec<(Transaction, u64)>, Box<dyn std::error::Error>> {
+    let entries = client.get_raw_mempool_verbose()?;
+    let mut cache = MEMPOOL.lock().map_err(|e| e.to_string())?;
+    let cache = cache.get_or_insert_with(HashMap::new);
+    cache.retain(|txid, _| entries.contains_key(txid));
+    for (txid, entry) in entries {
+        if cache.contains_key(&txid) {
+            continue;
+        }
+        // It may have left the mempool since getrawmempool
+        if let Ok(tx) = client.get_raw_transaction(&txid, None) {
+            cache.insert(txid, (tx, entry.time));
+        }
+    }
+    Ok(cache.values().cloned().collect())
+}
@@ -1,120 +1,363 @@
+mod accounting;
 mod batch;
+mod cli;
 mod client;
+mod config;
+mod filters;
 mod node;
 mod payjoin;
+mod seed;
 mod wallet;
+mod weight;
 
-use std::env;
+use std::time::Duration;
 
-use bdk_wallet::bitcoin::Amount;
+use bdk_wallet::bitcoin::{address::NetworkUnchecked, Address, Amount};
+use bitcoincore_rpc::Client;
+use clap::Parser;
 
 use batch::methods;
-use client::{bitcoind_client, fund_client, get_client_balance, wait_for_block};
+use cli::{
+    effective_fee_rate, Cli, Command, DirectArgs, PayjoinArgs, SeedCommand, SessionsCommand,
+    UriCommand, V1ServeArgs, V2MergeArgs,
+};
+use client::{bitcoind_client, fund_client, fund_miner, get_client_balance, wait_for_block};
+use config::config;
 use node::{payjoin_batch, payjoin_open_channel};
-use payjoin::{direct::direct_payjoin, payjoin_v1::do_payjoin_v1, payjoin_v2::do_payjoin_v2};
-use wallet::{create_wallet, fund_wallet, sync_wallet, wallet_total_balance};
+use payjoin::{
+    direct::direct_payjoin,
+    merge::{do_payjoin_v2_merge, MergeSender},
+    payjoin_v1::{do_payjoin_v1, serve_v1},
+    payjoin_v2::{cancel_v2_session, do_payjoin_v2, list_v2_sessions, resume_v2_session},
+    server::TlsIdentity,
+    uri::{self, UriParams},
+};
+use wallet::{fund_wallet, participant_wallet, sync_wallet, wallet_total_balance};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse();
+    config::init(cli.config.as_deref())?;
 
-    let mut op = "directly";
-    if args.len() >= 2 {
-        op = &args[1];
+    // URI tooling needs no node
+    if let Command::Uri(command) = cli.command {
+        return run_uri(command);
     }
-
-    let mut sub_op = "1";
-    if args.len() >= 3 {
-        sub_op = &args[2];
+    if let Command::Seed(command) = cli.command {
+        return run_seed(command);
     }
 
-    let miner = bitcoind_client("miner").unwrap();
-
-    let sender_seed = &[0u8; 64];
-    let receiver_seed = &[1u8; 64];
+    let miner = bitcoind_client(&config().miner_wallet).unwrap();
+    fund_miner(&miner, Amount::from_int_btc(50))?;
 
-    let mut funded = false;
-    let amount_to_send: Amount = Amount::from_sat(100_000);
-
-    if op == "ldk" {
-        payjoin_batch(&miner)?;
-    } else if op == "ldk-open-channel" {
-        payjoin_open_channel(&miner)?;
-    } else if op == "batch" {
-        match sub_op {
-            "1" => methods::method_1(&miner)?,
-            "2" => methods::method_2(&miner)?,
-            "3" => methods::method_3(&miner)?,
-            "4" => methods::method_4(&miner)?,
-            "5" => methods::method_5(&miner)?,
-            "6" => methods::method_6(&miner)?,
-            _ => println!("ERROR(batch(method)): Invalid method!"),
+    match cli.command {
+        Command::Ldk(args) => payjoin_batch(&miner, &args)?,
+        Command::LdkOpenChannel(args) => payjoin_open_channel(&miner, &args)?,
+        Command::Batch(args) => match args.method {
+            1 => methods::method_1(&miner, &args)?,
+            2 => methods::method_2(&miner, &args)?,
+            3 => methods::method_3(&miner, &args)?,
+            4 => methods::method_4(&miner, &args)?,
+            5 => methods::method_5(&miner, &args)?,
+            6 => methods::method_6(&miner, &args)?,
+            _ => unreachable!("batch method is range checked by the cli"),
+        },
+        Command::Direct(args) => run_direct(&miner, &args)?,
+        Command::V1(args) => {
+            let (sender, receiver) = setup_clients(&miner, &args)?;
+            // Payjoin V1 (rust-payjoin)
+            println!("===== V1 =====");
+            do_payjoin_v1(
+                &miner,
+                &sender,
+                &receiver,
+                args.receiver_wallet_name(),
+                args.amount,
+                effective_fee_rate(args.fee_rate),
+                &args.sender_params.params_v1(),
+                &args.forwards,
+            )
+            .await?;
         }
-    } else if op == "directly" {
-        // Direct Payjoin (bdk_wallet only)
-        println!("===== Payjoin Directly =====");
-        let mut sender = create_wallet(sender_seed)?;
-        let mut receiver = create_wallet(receiver_seed)?;
-
-        if wallet_total_balance(&miner, &mut sender)? < amount_to_send {
-            match fund_wallet(&miner, &mut sender, Amount::from_sat(1_000_000), 25) {
-                Ok(_) => {}
-                Err(err) => println!("ERROR(fund_wallet(sender)): {:?}", err),
-            };
-            funded = true;
+        Command::V1Serve(args) => run_v1_server(&args)?,
+        Command::V2(args) => {
+            let (sender, receiver) = setup_clients(&miner, &args)?;
+            // Payjoin V2 (rust-payjoin)
+            println!("===== V2 =====");
+            do_payjoin_v2(
+                &miner,
+                &sender,
+                &receiver,
+                args.sender_wallet_name(),
+                args.receiver_wallet_name(),
+                args.amount,
+                effective_fee_rate(args.fee_rate),
+                &args.sender_params.params_v2(),
+                &args.forwards,
+            )
+            .await?;
         }
-
-        if wallet_total_balance(&miner, &mut receiver)? < amount_to_send {
-            match fund_wallet(&miner, &mut receiver, Amount::from_sat(500_000), 25) {
-                Ok(_) => {}
-                Err(err) => println!("ERROR(fund_wallet(receiver)): {:?}", err),
-            };
-            funded = true;
+        Command::V2Merge(args) => run_v2_merge(&miner, &args).await?,
+        Command::Uri(_) | Command::Seed(_) => {
+            unreachable!("handled before connecting to bitcoind")
         }
+        Command::V2Sessions(command) => match command {
+            SessionsCommand::List => list_v2_sessions()?,
+            SessionsCommand::Resume { id } => resume_v2_session(&id).await?,
+            SessionsCommand::Cancel {
+                id,
+                broadcast_original,
+            } => cancel_v2_session(&id, broadcast_original)?,
+        },
+    }
 
-        if funded {
-            wait_for_block(&miner, 2)?;
-        }
+    Ok(())
+}
 
-        sync_wallet(&miner, &mut sender, funded)?;
-        sync_wallet(&miner, &mut receiver, funded)?;
-
-        direct_payjoin(&miner, &mut sender, &mut receiver, amount_to_send)?;
-    } else {
-        println!("===== Payjoin V1/V2 =====");
-        let sender = bitcoind_client("sender").unwrap();
-        let receiver = bitcoind_client("receiver").unwrap();
-
-        if get_client_balance(&sender)? < amount_to_send {
-            match fund_client(&miner, &sender, Amount::from_sat(1_000_000), 25) {
-                Ok(_) => {}
-                Err(err) => println!("ERROR(fund_client(sender)): {:?}", err),
-            };
-            funded = true;
+fn run_uri(command: UriCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let network = config().network;
+    match command {
+        UriCommand::Generate(args) => {
+            let address = args
+                .address
+                .parse::<Address<NetworkUnchecked>>()?
+                .require_network(network)?;
+            let uri = uri::generate(
+                &UriParams {
+                    address,
+                    amount: args.amount,
+                    label: args.label,
+                    message: args.message,
+                    pj: args.pj,
+                    disable_output_substitution: args.disable_output_substitution,
+                },
+                network,
+            )?;
+            println!("{}", uri);
+            if args.qr {
+                println!("{}", uri::render_qr(&uri, args.ascii)?);
+            }
         }
-
-        if get_client_balance(&receiver)? < amount_to_send {
-            match fund_client(&miner, &receiver, Amount::from_sat(500_000), 25) {
-                Ok(_) => {}
-                Err(err) => println!("ERROR(fund_client(receiver)): {:?}", err),
-            };
-            funded = true;
+        UriCommand::Parse { uri, qr } => {
+            println!("{}", uri::parse(&uri, network)?);
+            if qr {
+                println!("{}", uri::render_qr(&uri, false)?);
+            }
         }
+        UriCommand::Qr { data, ascii } => println!("{}", uri::render_qr(&data, ascii)?),
+    }
+    Ok(())
+}
 
-        if funded {
-            wait_for_block(&miner, 2)?;
+fn run_seed(command: SeedCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        SeedCommand::Generate {
+            name,
+            words,
+            passphrase,
+        } => {
+            let mnemonic = seed::generate(&name, words, passphrase)?;
+            println!("[Seed] Write it down, it is only shown again by `seed show`:");
+            println!("{}", *mnemonic);
         }
-
-        if op == "v1" {
-            // Payjoin V1 (rust-payjoin)
-            println!("===== V1 =====");
-            do_payjoin_v1(&sender, &receiver, amount_to_send, false)?;
-        } else if op == "v2" {
-            // Payjoin V2 (rust-payjoin)
-            println!("===== V2 =====");
-            do_payjoin_v2(&sender, &receiver, amount_to_send).await?;
+        SeedCommand::Import { name, passphrase } => seed::import(&name, passphrase)?,
+        SeedCommand::Show { name } => {
+            let (mnemonic, passphrase) = seed::show(&name)?;
+            println!("{}", *mnemonic);
+            if !passphrase.is_empty() {
+                println!("passphrase: {}", *passphrase);
+            }
+        }
+        SeedCommand::List => {
+            for (name, fingerprint, created_at) in seed::list()? {
+                println!(
+                    "{} | fingerprint={} | created_at={}",
+                    name, fingerprint, created_at
+                );
+            }
         }
     }
+    Ok(())
+}
+
+fn run_direct(miner: &Client, args: &DirectArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // Direct Payjoin (bdk_wallet only)
+    println!("===== Payjoin Directly =====");
+    let mut sender = participant_wallet(
+        "sender",
+        args.sender_seed_name.as_deref(),
+        args.sender_seed,
+        args.sender_script_type,
+    )?;
+    let mut receiver = participant_wallet(
+        "receiver",
+        args.receiver_seed_name.as_deref(),
+        args.receiver_seed,
+        args.receiver_script_type,
+    )?;
+    println!(
+        "[Payjoin] Sender {} | Receiver {}",
+        args.sender_script_type, args.receiver_script_type
+    );
+
+    let mut funded = false;
+
+    if wallet_total_balance(miner, &mut sender)? < args.amount {
+        match fund_wallet(miner, &mut sender, Amount::from_sat(1_000_000), 25) {
+            Ok(_) => {}
+            Err(err) => println!("ERROR(fund_wallet(sender)): {:?}", err),
+        };
+        funded = true;
+    }
+
+    if wallet_total_balance(miner, &mut receiver)? < args.amount {
+        match fund_wallet(miner, &mut receiver, Amount::from_sat(500_000), 25) {
+            Ok(_) => {}
+            Err(err) => println!("ERROR(fund_wallet(receiver)): {:?}", err),
+        };
+        funded = true;
+    }
+
+    if funded {
+        wait_for_block(miner, 2)?;
+    }
 
+    sync_wallet(miner, &mut sender, funded)?;
+    sync_wallet(miner, &mut receiver, funded)?;
+
+    direct_payjoin(
+        miner,
+        &mut sender,
+        &mut receiver,
+        args.amount,
+        effective_fee_rate(args.fee_rate),
+        args.sender_max_utxos,
+        args.receiver_max_utxos,
+    )?;
     Ok(())
 }
+
+fn run_v1_server(args: &V1ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsIdentity::from_files(cert, key)?),
+        _ if args.self_signed => Some(TlsIdentity::self_signed()?),
+        _ => None,
+    };
+    let receiver_wallet = args
+        .receiver_wallet
+        .clone()
+        .unwrap_or_else(|| config().receiver_wallet.clone());
+    serve_v1(
+        args.bind,
+        args.public_url.clone(),
+        &receiver_wallet,
+        args.amount,
+        tls,
+        &args.forwards,
+    )
+}
+
+async fn run_v2_merge(
+    miner: &Client,
+    args: &V2MergeArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("===== V2 Merge =====");
+    if !args.demo_only {
+        return Err(
+            "v2-merge signs the merged PSBT with the senders' bitcoind wallets in-process, \
+             it is a demo: pass --demo-only"
+                .into(),
+        );
+    }
+    let receiver_wallet = args
+        .receiver_wallet
+        .clone()
+        .unwrap_or_else(|| config().receiver_wallet.clone());
+    let receiver = bitcoind_client(&receiver_wallet)?;
+    let mut senders = Vec::with_capacity(args.senders.len());
+    let mut funded = fund_if_below(
+        miner,
+        &receiver,
+        args.amount,
+        Amount::from_sat(500_000),
+        "receiver",
+    );
+    for wallet in &args.senders {
+        let client = bitcoind_client(wallet)?;
+        funded |= fund_if_below(
+            miner,
+            &client,
+            args.amount,
+            Amount::from_sat(1_000_000),
+            wallet,
+        );
+        senders.push(MergeSender {
+            wallet: wallet.clone(),
+            client,
+        });
+    }
+    if funded {
+        wait_for_block(miner, 2)?;
+    }
+
+    do_payjoin_v2_merge(
+        &senders,
+        &receiver,
+        &receiver_wallet,
+        args.amount,
+        effective_fee_rate(args.fee_rate),
+        &args.sender_params.params_v2(),
+        Duration::from_secs(args.window_secs),
+        args.drop_out.as_deref(),
+    )
+    .await
+}
+
+/// Sends 25 UTXOs of `utxo_amount` to the client when its balance is below `amount`, true if it did.
+fn fund_if_below(
+    miner: &Client,
+    client: &Client,
+    amount: Amount,
+    utxo_amount: Amount,
+    name: &str,
+) -> bool {
+    match get_client_balance(client) {
+        Ok(balance) if balance >= amount => return false,
+        Ok(_) => {}
+        Err(err) => println!("ERROR(get_client_balance({})): {:?}", name, err),
+    }
+    if let Err(err) = fund_client(miner, client, utxo_amount, 25) {
+        println!("ERROR(fund_client({})): {:?}", name, err);
+    }
+    true
+}
+
+fn setup_clients(
+    miner: &Client,
+    args: &PayjoinArgs,
+) -> Result<(Client, Client), Box<dyn std::error::Error>> {
+    println!("===== Payjoin V1/V2 =====");
+    let sender = bitcoind_client(args.sender_wallet_name()).unwrap();
+    let receiver = bitcoind_client(args.receiver_wallet_name()).unwrap();
+
+    let mut funded = fund_if_below(
+        miner,
+        &sender,
+        args.amount,
+        Amount::from_sat(1_000_000),
+        "sender",
+    );
+    funded |= fund_if_below(
+        miner,
+        &receiver,
+        args.amount,
+        Amount::from_sat(500_000),
+        "receiver",
+    );
+
+    if funded {
+        wait_for_block(miner, 2)?;
+    }
+
+    Ok((sender, receiver))
+}
@@ -1,7 +1,6 @@
-use std::thread::sleep;
 use std::time::Duration;
 
-use bdk_wallet::KeychainKind;
+use bdk_wallet::{bitcoin::bip32::Xpriv, template::Bip84, KeychainKind, Wallet};
 use bitcoincore_rpc::{Client, RpcApi};
 
 use ldk_node::config::Config;
@@ -14,33 +13,41 @@ use ldk_node::{
     bitcoin::{
         key::rand::{thread_rng, Rng},
         locktime::absolute::LockTime,
-        policy::DEFAULT_MIN_RELAY_TX_FEE,
-        Amount, FeeRate, Network, Psbt,
+        Amount, FeeRate, Psbt, SignedAmount,
     },
     UserChannelId,
 };
 use ldk_node::{Builder, Node};
 
 use crate::{
-    client::wait_for_block,
-    wallet::{create_wallet, wallet_total_balance},
+    accounting::{psbt_prevouts, Accounting, NetworkFee},
+    cli::{effective_fee_rate, LdkBatchArgs, LdkOpenChannelArgs},
+    client::{wait_for_block, wait_for_funds},
+    config::config,
+    seed::node_seed,
+    wallet::{create_descriptor_wallet, participant_wallet, read_descriptor, wallet_total_balance},
+    weight::{FeeCheck, Signatures},
 };
 
 const CHANNEL_READY_CONFIRMATION_BLOCKS: u64 = 6;
+/// Scripts derived per keychain when mirroring a node's wallet, the nodes reveal new addresses
+/// every run
+const NODE_WALLET_LOOKAHEAD: u32 = 1_000;
 
 fn get_config(
     node_alias: &str,
     port_in: u16,
     port_out: u16,
 ) -> Result<Config, Box<dyn std::error::Error>> {
+    let app_config = config();
     let mut config = Config::default();
 
-    config.network = Network::Signet;
+    config.network = app_config.network;
     println!("Setting network: {}", config.network);
 
-    let rand_dir = format!("data/{}", node_alias);
-    println!("Setting random LDK storage dir: {}", rand_dir);
-    config.storage_dir_path = rand_dir;
+    let rand_dir = app_config.data_dir.join(node_alias);
+    println!("Setting random LDK storage dir: {:?}", rand_dir);
+    config.storage_dir_path = rand_dir.to_string_lossy().to_string();
 
     let address: Vec<SocketAddress> = vec![
         format!("0.0.0.0:{}", port_in).parse().unwrap(),
@@ -59,19 +66,28 @@ fn get_config(
     Ok(config)
 }
 
-fn setup_nodes(count: u8, mut port: u16) -> Result<Vec<Node>, Box<dyn std::error::Error>> {
+/// Nodes `0..count`, seeded from the stored seeds `<seed_prefix>-<idx>` or the test seeds.
+fn setup_nodes(
+    count: u8,
+    mut port: u16,
+    seed_prefix: Option<&str>,
+) -> Result<Vec<Node>, Box<dyn std::error::Error>> {
+    let (rpc_host, rpc_port) = config().rpc_host_port()?;
+    let (rpc_user, rpc_pass) = config().rpc_auth.credentials()?;
     let mut nodes = vec![];
     for i in 0..count {
         let node_alias = format!("node-{}", i);
         let mut builder = Builder::from_config(get_config(node_alias.as_str(), port, port + 1)?);
         builder.set_chain_source_bitcoind_rpc(
-            "0.0.0.0".to_string(),
-            38332,
-            "local".to_string(),
-            "local".to_string(),
+            rpc_host.clone(),
+            rpc_port,
+            rpc_user.clone(),
+            rpc_pass.clone(),
         );
-        let seed_bytes = &[i; 64];
-        builder.set_entropy_seed_bytes(seed_bytes.to_vec())?;
+        let (_, seed) = node_seed(seed_prefix, i)?;
+        // ldk-node takes the seed as an owned Vec it drops unwiped (and keeps its keys for the
+        // node's lifetime): that copy can't be zeroized, ours is on drop
+        builder.set_entropy_seed_bytes(seed.to_vec())?;
         let node = builder.build()?;
 
         println!("[LDK-Node Payjoin][{}] Starting...", node_alias);
@@ -149,6 +165,38 @@ fn open_channel(
     Ok(user_channel_id)
 }
 
+/// The nodes build and sign the PSBT themselves: check what it ends up paying against the target,
+/// refusing anything below it.
+fn check_fee(psbt: &Psbt, fee_rate: FeeRate) -> Result<(), Box<dyn std::error::Error>> {
+    let check = FeeCheck::new(psbt, Signatures::Max)?;
+    println!(
+        "[LDK-Node Payjoin] Fee check: {} (target {} sat/vB)",
+        check,
+        fee_rate.to_sat_per_vb_ceil()
+    );
+    check.ensure(fee_rate)
+}
+
+/// In-memory mirror of the on-chain wallet of the node `idx`: ldk-node derives it (BIP84) from the
+/// same seed, so it tells the node's scripts apart.
+fn node_wallet(seed_prefix: Option<&str>, idx: u8) -> Result<Wallet, Box<dyn std::error::Error>> {
+    let (_, seed) = node_seed(seed_prefix, idx)?;
+    let mut xprv = Xpriv::new_master(config().network, &seed[..])?;
+    let wallet = Wallet::create(
+        Bip84(xprv, KeychainKind::External),
+        Bip84(xprv, KeychainKind::Internal),
+    )
+    .network(config().network)
+    .lookahead(NODE_WALLET_LOOKAHEAD)
+    .create_wallet_no_persist()?;
+    xprv.private_key.non_secure_erase();
+    Ok(wallet)
+}
+
+fn onchain_balance(node: &Node) -> Amount {
+    Amount::from_sat(node.list_balances().total_onchain_balance_sats)
+}
+
 fn connect_nodes(node_a: &Node, node_b: &Node) -> Result<(), Box<dyn std::error::Error>> {
     let counterparty_address = node_b
         .listening_addresses()
@@ -160,13 +208,16 @@ fn connect_nodes(node_a: &Node, node_b: &Node) -> Result<(), Box<dyn std::error:
     Ok(())
 }
 
-pub fn payjoin_batch(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
+pub fn payjoin_batch(
+    bitcoind: &Client,
+    args: &LdkBatchArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
     println!("[LDK-Node Payjoin] Setting up Sender and Receiver wallets...");
 
     // Node5 is the Sender
     let sender_node_idx = 7;
 
-    let mut nodes = setup_nodes(8, 7777)?;
+    let mut nodes = setup_nodes(8, 7777, args.node_seed_prefix.as_deref())?;
 
     let funding_amount = Amount::from_sat(1_000_000);
 
@@ -198,21 +249,30 @@ pub fn payjoin_batch(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>
     open_channel(&nodes[4], &nodes[5], Amount::from_sat(500_000))?;
     open_channel(&nodes[5], &nodes[6], Amount::from_sat(500_000))?;
 
-    let mut receiver = create_wallet(&[255u8; 64])?;
+    // The receiver never signs, it can be watch-only
+    let mut receiver = match &args.receiver_descriptor {
+        Some(descriptor) => create_descriptor_wallet(read_descriptor(descriptor)?, None)?,
+        None => participant_wallet(
+            "receiver",
+            args.receiver_seed_name.as_deref(),
+            args.receiver_seed,
+            args.receiver_script_type,
+        )?,
+    };
 
     // Sender wants to batch UTXOs
-    let amount = Amount::from_sat(777_777);
+    let amount = args.amount;
     let script_pubkey = receiver
         .reveal_next_address(KeychainKind::External)
         .address
         .script_pubkey();
 
-    let fee_per_participant = Amount::from_sat(99_999);
-    let max_participants = 6;
+    let fee_per_participant = args.fee_per_participant;
+    let max_participants = args.participants.into();
 
-    let fee_rate = FeeRate::from_sat_per_vb(DEFAULT_MIN_RELAY_TX_FEE as u64).unwrap();
+    let fee_rate = effective_fee_rate(args.fee_rate);
     let locktime = LockTime::ZERO;
-    let max_utxo_count = 4;
+    let max_utxo_count = args.max_utxos.into();
 
     // Sender must start the Batch workflow by selecting an initial Node
     let initial_node_idx = 4;
@@ -260,6 +320,7 @@ pub fn payjoin_batch(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>
     let psbt_hex = batch_psbts.first().unwrap();
 
     let psbt = Psbt::deserialize(&hex::decode(psbt_hex).unwrap()).unwrap();
+    check_fee(&psbt, fee_rate)?;
 
     println!("[LDK-Node Payjoin] Extracting Tx...\n");
     let tx = psbt.clone().extract_tx()?;
@@ -281,6 +342,21 @@ pub fn payjoin_batch(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>
         ));
     }
 
+    // The sender node pays the receiver, a fee to every node it batched with and the network fee.
+    // The nodes are registered once the tx confirmed, when we know which ones were batched.
+    let mut accounting = Accounting::new();
+    let rcv = accounting.participant(
+        "receiver",
+        amount.to_signed()?,
+        SignedAmount::ZERO,
+        NetworkFee::None,
+        receiver_initial_balance,
+        true,
+    );
+    accounting.record_tx(&tx, &psbt_prevouts(&psbt)?, |script| {
+        Ok(receiver.is_mine(script.to_owned()).then_some(rcv))
+    })?;
+
     println!("\nTx Inputs/Outputs:\n");
     for input in tx.input.iter() {
         let tx_info = bitcoind.get_raw_transaction_info(&input.previous_output.txid, None)?;
@@ -308,12 +384,12 @@ pub fn payjoin_batch(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>
         node.sync_wallets()?;
     }
 
-    let balance = wallet_total_balance(bitcoind, &mut receiver)?;
+    let receiver_balance = wallet_total_balance(bitcoind, &mut receiver)?;
     println!(
         "\n[LDK-Node Payjoin] Receiver Balances (b/a/delta): {} | {} | {}\n",
         receiver_initial_balance,
-        balance,
-        balance - receiver_initial_balance,
+        receiver_balance,
+        receiver_balance - receiver_initial_balance,
     );
 
     for (idx, node) in nodes.iter_mut().enumerate() {
@@ -338,16 +414,66 @@ pub fn payjoin_batch(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>
         }
     }
 
+    let balances_after: Vec<Amount> = nodes.iter().map(onchain_balance).collect();
+    // The nodes spending an input of the batch PSBT joined it and each earns its fee, whatever
+    // their balances did
+    let prevouts = psbt_prevouts(&psbt)?;
+    let mut batched = vec![];
+    for idx in 0..nodes.len() {
+        let wallet = node_wallet(args.node_seed_prefix.as_deref(), idx as u8)?;
+        batched.push(
+            idx != sender_node_idx
+                && prevouts
+                    .iter()
+                    .any(|prevout| wallet.is_mine(prevout.script_pubkey.clone())),
+        );
+    }
+    let batched_count = batched.iter().filter(|batched| **batched).count() as u64;
+    println!(
+        "[LDK-Node Payjoin] {} node(s) batched with the sender",
+        batched_count
+    );
+    for (idx, before) in nodes_balance.iter().enumerate() {
+        let (payment, participant_fee, network_fee) = if idx == sender_node_idx {
+            (
+                -amount.to_signed()?,
+                -(fee_per_participant * batched_count).to_signed()?,
+                NetworkFee::Rest,
+            )
+        } else if batched[idx] {
+            (
+                SignedAmount::ZERO,
+                fee_per_participant.to_signed()?,
+                NetworkFee::None,
+            )
+        } else {
+            (SignedAmount::ZERO, SignedAmount::ZERO, NetworkFee::None)
+        };
+        accounting.participant(
+            format!("node {}", idx),
+            payment,
+            participant_fee,
+            network_fee,
+            *before,
+            false,
+        );
+    }
+
     println!("\n[LDK-Node Payjoin] Stopping Nodes...");
     for node in nodes {
         node.stop()?;
     }
 
-    Ok(())
+    let mut balances = vec![receiver_balance];
+    balances.extend(balances_after);
+    accounting.verify(&balances)
 }
 
-pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error::Error>> {
-    let nodes = setup_nodes(2, 7000)?;
+pub fn payjoin_open_channel(
+    bitcoind: &Client,
+    args: &LdkOpenChannelArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let nodes = setup_nodes(2, 7000, args.node_seed_prefix.as_deref())?;
 
     let node_a = &nodes[0];
     let node_b = &nodes[1];
@@ -383,7 +509,7 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
             txid
         );
         loop {
-            sleep(Duration::from_secs(10));
+            wait_for_funds(bitcoind, Duration::from_secs(10))?;
             println!(
                 "[LDK-Node Payjoin] NodeA({:?}) sync_wallets()",
                 node_a_address
@@ -407,7 +533,7 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
     bitcoind.send_to_address(&node_b_address, amount, None, None, None, None, None, None)?;
 
     println!("[LDK-Node Payjoin] sync_wallets()...");
-    sleep(Duration::from_secs(12));
+    wait_for_funds(bitcoind, Duration::from_secs(12))?;
     node_a.sync_wallets()?;
     node_b.sync_wallets()?;
     println!("[LDK-Node Payjoin] sync_wallets() -> Done");
@@ -416,7 +542,7 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
     node_a
         .payjoin_set_current_channel_info(ChannelId::new_zero(), node_b_address.script_pubkey())?;
 
-    let amount = 777_777;
+    let amount = args.amount.to_sat();
 
     let counterparty_node_id = node_b.node_id();
     let counterparty_address = node_b
@@ -442,11 +568,13 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
 
     wait_for_block(&bitcoind, 2)?;
 
+    let mut funding_accounting = None;
+
     // The FundingGenerationReady event will be triggered and we will get the necessary data (channelId, scriptbuf) to fund the channel
     if let Some((channel_id, channel_output_script)) = node_a.payjoin_get_current_channel_info()? {
         println!("[LDK-Node Payjoin] ChannelId (A <-> B): {:?}", channel_id);
 
-        let fee_rate = FeeRate::from_sat_per_vb(DEFAULT_MIN_RELAY_TX_FEE as u64).unwrap();
+        let fee_rate = effective_fee_rate(args.fee_rate);
         let locktime = LockTime::ZERO;
         let mut psbt = node_a.payjoin_build_psbt(
             channel_output_script,
@@ -474,6 +602,7 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
         node_a.payjoin_sign_psbt(&mut psbt)?;
         println!("[LDK-Node Payjoin] NodeB signing...");
         node_b.payjoin_sign_psbt(&mut psbt)?;
+        check_fee(&psbt, fee_rate)?;
 
         println!(
             "[LDK-Node Payjoin] NodeA({:?}) (pre-open-channel): {:?} sats",
@@ -481,6 +610,25 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
             node_a.list_balances().spendable_onchain_balance_sats
         );
 
+        // NodeA funds the channel, NodeB only adds inputs (and gets them back as change); both
+        // may pay for their own inputs
+        let mut accounting = Accounting::new();
+        for (name, payment, node) in [
+            ("NodeA", -Amount::from_sat(amount).to_signed()?, node_a),
+            ("NodeB", SignedAmount::ZERO, node_b),
+        ] {
+            accounting.participant(
+                name,
+                payment,
+                SignedAmount::ZERO,
+                NetworkFee::Rest,
+                onchain_balance(node),
+                false,
+            );
+        }
+        accounting.record_tx(&psbt.unsigned_tx, &psbt_prevouts(&psbt)?, |_| Ok(None))?;
+        funding_accounting = Some(accounting);
+
         // Use the Payjoin PSBT as the channel's funding transaction
         node_a.payjoin_fund_channel(channel_id, node_b.node_id(), psbt)?;
 
@@ -507,6 +655,10 @@ pub fn payjoin_open_channel(bitcoind: &Client) -> Result<(), Box<dyn std::error:
     );
     node_b.sync_wallets()?;
 
+    if let Some(accounting) = funding_accounting {
+        accounting.verify(&[onchain_balance(node_a), onchain_balance(node_b)])?;
+    }
+
     println!(
         "[LDK-Node Payjoin] NodeA({:?}) (pos-open-channel): {:?} sats",
         node_a_address,
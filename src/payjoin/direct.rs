@@ -1,16 +1,14 @@
 use bdk_wallet::{
-    bitcoin::{
-        policy::DEFAULT_MIN_RELAY_TX_FEE,
-        psbt::{Input, Output},
-        Amount, FeeRate, TxIn, TxOut,
-    },
+    bitcoin::{psbt::Output, Amount, FeeRate, SignedAmount, TxOut},
     KeychainKind, SignOptions, Wallet,
 };
 use bitcoincore_rpc::{Client, RpcApi};
 
 use crate::{
+    accounting::{psbt_prevouts, Accounting, NetworkFee},
     client::wait_for_block,
-    wallet::{get_wallet_utxos, wallet_total_balance},
+    wallet::{get_wallet_utxos, psbt_input, wallet_total_balance},
+    weight::{FeeCheck, Signatures},
 };
 
 pub fn direct_payjoin(
@@ -18,6 +16,9 @@ pub fn direct_payjoin(
     sender: &mut Wallet,
     receiver: &mut Wallet,
     amount: Amount,
+    fee_rate: FeeRate,
+    sender_max_utxos: u16,
+    receiver_max_utxos: u16,
 ) -> Result<bool, Box<dyn std::error::Error>> {
     let sender_utxos = get_wallet_utxos(&sender);
 
@@ -27,7 +28,7 @@ pub fn direct_payjoin(
         .script_pubkey();
     let mut builder = sender.build_tx();
     builder.add_recipient(script_pubkey.clone(), amount);
-    builder.fee_rate(FeeRate::from_sat_per_vb(DEFAULT_MIN_RELAY_TX_FEE as u64).unwrap());
+    builder.fee_rate(fee_rate);
     builder.manually_selected_only();
 
     // Add sender's UTXOs
@@ -41,12 +42,13 @@ pub fn direct_payjoin(
         builder.add_utxo(utxo.outpoint).unwrap();
         sender_utxos_value += utxo.txout.value;
         count += 1;
-        if count >= 3 {
+        if count >= sender_max_utxos {
             break;
         }
     }
 
     let mut psbt = builder.finish().unwrap();
+    let sender_fee = psbt.fee()?;
 
     // Add receiver's UTXOs
     let mut count = 0;
@@ -56,27 +58,19 @@ pub fn direct_payjoin(
             "[Payjoin] Adding receiver UTXO [txid={:?} | vout={:?}]",
             utxo.outpoint.txid, utxo.outpoint.vout
         );
-        let tx = bitcoind.get_raw_transaction(&utxo.outpoint.txid, None)?;
-        let input = TxIn {
-            previous_output: utxo.outpoint,
-            script_sig: Default::default(),
-            sequence: Default::default(),
-            witness: Default::default(),
-        };
-        psbt.inputs.push(Input {
-            non_witness_utxo: Some(tx),
-            ..Default::default()
-        });
-        psbt.unsigned_tx.input.push(input);
+        let (txin, input) = psbt_input(receiver, &utxo)?;
+        psbt.inputs.push(input);
+        psbt.unsigned_tx.input.push(txin);
         receiver_utxos_value += utxo.txout.value;
 
         count += 1;
-        if count >= 2 {
+        if count >= receiver_max_utxos {
             break;
         }
     }
 
     // Output
+    let mut receiver_fee = Amount::ZERO;
     println!("[Payjoin] Adding receiver output (sending amount + receiver's UTXO values) [amount={:?} | value={:?}]", amount.to_btc(), receiver_utxos_value.to_btc());
     for (idx, out) in psbt.clone().unsigned_tx.output.iter().enumerate() {
         if out.script_pubkey == script_pubkey {
@@ -92,9 +86,21 @@ pub fn direct_payjoin(
                 ..Default::default()
             };
             psbt.unsigned_tx.output[idx] = output;
+
+            // The sender's fee only covers its own inputs, the receiver pays for the ones it added
+            let missing = FeeCheck::new(&psbt, Signatures::Max)?.missing(fee_rate)?;
+            let value = &mut psbt.unsigned_tx.output[idx].value;
+            *value = value
+                .checked_sub(missing)
+                .ok_or("Receiver output can't pay for its inputs")?;
+            println!("[Payjoin] Receiver paying {} for its inputs", missing);
+            receiver_fee = missing;
             break;
         }
     }
+    let check = FeeCheck::new(&psbt, Signatures::Max)?;
+    println!("[Payjoin] Fee check: {}", check);
+    check.ensure(fee_rate)?;
 
     println!("[Payjoin] Sender signing PSBT...");
     sender.sign(&mut psbt, SignOptions::default()).unwrap();
@@ -107,16 +113,40 @@ pub fn direct_payjoin(
         .finalize_psbt(&mut psbt, SignOptions::default())
         .unwrap();
 
-    println!(
-        "[Payjoin] Snd(before): {:?}",
-        wallet_total_balance(&bitcoind, sender)?.to_btc()
+    let sender_before = wallet_total_balance(&bitcoind, sender)?;
+    let receiver_before = wallet_total_balance(&bitcoind, receiver)?;
+    println!("[Payjoin] Snd(before): {:?}", sender_before.to_btc());
+    println!("[Payjoin] Rcv(before): {:?}", receiver_before.to_btc());
+
+    let mut accounting = Accounting::new();
+    let payment = amount.to_signed()?;
+    let snd = accounting.participant(
+        "sender",
+        -payment,
+        SignedAmount::ZERO,
+        NetworkFee::UpTo(sender_fee),
+        sender_before,
+        true,
     );
-    println!(
-        "[Payjoin] Rcv(before): {:?}",
-        wallet_total_balance(&bitcoind, receiver)?.to_btc()
+    let rcv = accounting.participant(
+        "receiver",
+        payment,
+        SignedAmount::ZERO,
+        NetworkFee::UpTo(receiver_fee),
+        receiver_before,
+        true,
     );
 
     let tx = psbt.clone().extract_tx()?;
+    accounting.record_tx(&tx, &psbt_prevouts(&psbt)?, |script| {
+        Ok(if sender.is_mine(script.to_owned()) {
+            Some(snd)
+        } else if receiver.is_mine(script.to_owned()) {
+            Some(rcv)
+        } else {
+            None
+        })
+    })?;
     println!("[Payjoin] Sending Tx...");
     bitcoind.send_raw_transaction(&tx).unwrap();
 
@@ -130,10 +160,9 @@ pub fn direct_payjoin(
         fee.to_btc(),
         (sender_balance + fee).to_btc()
     );
-    println!(
-        "[Payjoin] Rcv(after) : {:?}",
-        wallet_total_balance(&bitcoind, receiver)?.to_btc()
-    );
+    let receiver_balance = wallet_total_balance(&bitcoind, receiver)?;
+    println!("[Payjoin] Rcv(after) : {:?}", receiver_balance.to_btc());
 
+    accounting.verify(&[sender_balance, receiver_balance])?;
     Ok(true)
 }
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use payjoin::Url;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request as HttpRequest, Response, Server, SslConfig};
+
+use crate::config::config;
+
+use super::{
+    payjoin_v1::BoxError,
+    payjoin_v2::V2_SESSION_EXPIRY,
+    server::TlsIdentity,
+    store::{load_json, now, save_private_json},
+};
+
+const LOCAL_DIRECTORY_FILE: &str = "local_directory.json";
+/// A mailbox is dropped this long after it was first read (secs), its reader has moved on
+const CONSUMED_MAILBOX_EXPIRY: u64 = 60;
+
+/// Size of every OHTTP response body, rust-payjoin only accepts responses padded to it.
+const ENCAPSULATED_MESSAGE_BYTES: usize = 8192;
+// nonce (32 bytes) + AEAD tag (16 bytes) of the encapsulated response
+const PADDED_BHTTP_BYTES: usize = ENCAPSULATED_MESSAGE_BYTES - 48;
+
+/// Localhost stand-in for a BIP77 payjoin directory with its OHTTP gateway, so v2 runs offline.
+/// Every subdirectory is a mailbox: POST/PUT store the body, GET returns it (202 while empty).
+/// The OHTTP key and the mailboxes are kept in the data dir, so v2 sessions survive a restart.
+/// Mailboxes are dropped once their session expired or a minute after they were read.
+pub struct LocalDirectory {
+    server: Arc<Server>,
+    url: Url,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LocalDirectory {
+    pub fn start(bind: SocketAddr, tls: &TlsIdentity) -> Result<LocalDirectory, BoxError> {
+        let server = Server::https(
+            bind,
+            SslConfig {
+                certificate: tls.cert_pem.clone().into_bytes(),
+                private_key: tls.key_pem.clone().into_bytes(),
+            },
+        )
+        .map_err(|e| format!("Failed to bind payjoin directory on {}: {}", bind, e))?;
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or("Payjoin directory is not listening on an IP address")?;
+        let url = Url::parse(&format!("https://localhost:{}/", addr.port()))?;
+
+        let mut state = DirectoryState::load()?;
+        let gateway = Gateway::new(&state.ikm)?;
+        let server = Arc::new(server);
+        let handle = {
+            let server = server.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle_request(request, &gateway, &mut state);
+                }
+            })
+        };
+
+        println!("[Directory] Listening at {}", url);
+        Ok(LocalDirectory {
+            server,
+            url,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl Drop for LocalDirectory {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// What the local directory keeps across restarts.
+#[derive(Serialize, Deserialize)]
+struct DirectoryState {
+    /// Hex of the input keying material the OHTTP key is derived from
+    ikm: String,
+    /// Hex encoded mailbox contents, by subdirectory
+    mailboxes: HashMap<String, String>,
+    /// When each mailbox was written (unix secs)
+    #[serde(default)]
+    written_at: HashMap<String, u64>,
+    /// When each mailbox was first read (unix secs)
+    #[serde(default)]
+    read_at: HashMap<String, u64>,
+}
+
+impl Default for DirectoryState {
+    fn default() -> Self {
+        let mut ikm = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut ikm);
+        DirectoryState {
+            ikm: hex::encode(ikm),
+            mailboxes: HashMap::new(),
+            written_at: HashMap::new(),
+            read_at: HashMap::new(),
+        }
+    }
+}
+
+impl DirectoryState {
+    fn path() -> PathBuf {
+        config().data_dir.join(LOCAL_DIRECTORY_FILE)
+    }
+
+    fn load() -> Result<DirectoryState, BoxError> {
+        let mut state: DirectoryState = load_json(&Self::path())?;
+        state.prune();
+        // Persist a freshly generated key right away, sessions get created with it
+        state.save()?;
+        Ok(state)
+    }
+
+    /// Holds the OHTTP key and the (encrypted) session messages: owner only.
+    fn save(&self) -> Result<(), BoxError> {
+        save_private_json(&Self::path(), self)
+    }
+
+    /// Drops the mailboxes of expired sessions (or without a write time, from an older file) and
+    /// the ones read a while ago, true if any was.
+    fn prune(&mut self) -> bool {
+        let now = now();
+        let before = self.mailboxes.len();
+        let (written_at, read_at) = (&self.written_at, &self.read_at);
+        self.mailboxes.retain(|mailbox, _| {
+            let written = written_at.get(mailbox).copied().unwrap_or(0);
+            let consumed = read_at
+                .get(mailbox)
+                .is_some_and(|read| now >= read + CONSUMED_MAILBOX_EXPIRY);
+            now < written + V2_SESSION_EXPIRY.as_secs() && !consumed
+        });
+        let mailboxes = &self.mailboxes;
+        self.written_at
+            .retain(|mailbox, _| mailboxes.contains_key(mailbox));
+        self.read_at
+            .retain(|mailbox, _| mailboxes.contains_key(mailbox));
+        let pruned = before - self.mailboxes.len();
+        if pruned > 0 {
+            println!(
+                "[Directory] Pruned {} mailbox(es), {} left",
+                pruned,
+                self.mailboxes.len()
+            );
+        }
+        pruned > 0
+    }
+}
+
+/// OHTTP gateway of the directory: decapsulates the requests forwarded by the relay.
+struct Gateway {
+    server: ohttp::Server,
+    encoded_config: Vec<u8>,
+}
+
+impl Gateway {
+    fn new(ikm: &str) -> Result<Gateway, BoxError> {
+        let config = ohttp::KeyConfig::derive(
+            1,
+            ohttp::hpke::Kem::K256Sha256,
+            vec![ohttp::SymmetricSuite::new(
+                ohttp::hpke::Kdf::HkdfSha256,
+                ohttp::hpke::Aead::ChaCha20Poly1305,
+            )],
+            &hex::decode(ikm)?,
+        )?;
+        let encoded_config = config.encode()?;
+        Ok(Gateway {
+            server: ohttp::Server::new(config)?,
+            encoded_config,
+        })
+    }
+
+    /// Decapsulates the OHTTP request, serves the inner one and encapsulates the padded response.
+    fn handle(&self, body: &[u8], state: &mut DirectoryState) -> Result<Vec<u8>, BoxError> {
+        let (bhttp_req, response_ctx) = self.server.decapsulate(body)?;
+        let request = bhttp::Message::read_bhttp(&mut Cursor::new(&bhttp_req))?;
+        let method = String::from_utf8_lossy(request.control().method().unwrap_or_default());
+        let path = String::from_utf8_lossy(request.control().path().unwrap_or_default());
+        let mailbox = path.trim_matches('/').to_string();
+
+        let mut changed = state.prune();
+        let (status, content) = match method.as_ref() {
+            "POST" | "PUT" => {
+                println!("[Directory] {} /{}", method, mailbox);
+                state.written_at.insert(mailbox.clone(), now());
+                state.read_at.remove(&mailbox);
+                state
+                    .mailboxes
+                    .insert(mailbox, hex::encode(request.content()));
+                changed = true;
+                (200, vec![])
+            }
+            "GET" => match state.mailboxes.get(&mailbox) {
+                Some(content) => {
+                    println!("[Directory] GET /{} (200)", mailbox);
+                    let content = hex::decode(content)?;
+                    if !state.read_at.contains_key(&mailbox) {
+                        state.read_at.insert(mailbox, now());
+                        changed = true;
+                    }
+                    (200, content)
+                }
+                None => (202, vec![]),
+            },
+            _ => (404, vec![]),
+        };
+        if changed {
+            state.save()?;
+        }
+
+        let mut response = bhttp::Message::response(bhttp::StatusCode::try_from(status)?);
+        response.write_content(&content);
+        let mut bhttp_res = vec![];
+        response.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_res)?;
+        if bhttp_res.len() > PADDED_BHTTP_BYTES {
+            return Err(format!("Response too large ({} bytes)", bhttp_res.len()).into());
+        }
+        bhttp_res.resize(PADDED_BHTTP_BYTES, 0);
+        Ok(response_ctx.encapsulate(&bhttp_res)?)
+    }
+}
+
+fn handle_request(mut request: HttpRequest, gateway: &Gateway, state: &mut DirectoryState) {
+    let (method, url) = (request.method().clone(), request.url().to_string());
+    let response = match (method, url.as_str()) {
+        (Method::Get, "/ohttp-keys") => Response::from_data(gateway.encoded_config.clone())
+            .with_header(header("application/ohttp-keys")),
+        (Method::Post, "/") => {
+            let mut body = vec![];
+            match request
+                .as_reader()
+                .read_to_end(&mut body)
+                .map_err(BoxError::from)
+                .and_then(|_| gateway.handle(&body, state))
+            {
+                Ok(res) => Response::from_data(res).with_header(header("message/ohttp-res")),
+                Err(e) => {
+                    println!("[Directory] ERROR(gateway): {}", e);
+                    Response::from_data(vec![]).with_status_code(400)
+                }
+            }
+        }
+        _ => Response::from_data(vec![]).with_status_code(404),
+    };
+    if let Err(e) = request.respond(response) {
+        println!("[Directory] ERROR(respond): {}", e);
+    }
+}
+
+fn header(content_type: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .expect("content type header should be valid")
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// Receiver failures, one variant per well-known BIP78 error code.
+#[derive(Debug)]
+pub enum ReceiverError {
+    /// The payjoin endpoint is not available for now.
+    Unavailable(String),
+    /// The receiver added some inputs but could not bump the fee of the payjoin proposal.
+    NotEnoughMoney(String),
+    /// This version of payjoin is not supported.
+    VersionUnsupported(String),
+    /// The receiver rejected the original PSBT.
+    OriginalPsbtRejected(String),
+}
+
+impl ReceiverError {
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            ReceiverError::Unavailable(_) => "unavailable",
+            ReceiverError::NotEnoughMoney(_) => "not-enough-money",
+            ReceiverError::VersionUnsupported(_) => "version-unsupported",
+            ReceiverError::OriginalPsbtRejected(_) => "original-psbt-rejected",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ReceiverError::Unavailable(msg)
+            | ReceiverError::NotEnoughMoney(msg)
+            | ReceiverError::VersionUnsupported(msg)
+            | ReceiverError::OriginalPsbtRejected(msg) => msg,
+        }
+    }
+
+    /// BIP78 JSON error body: `{"errorCode": "...", "message": "..."}`.
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::json!({
+            "errorCode": self.error_code(),
+            "message": self.message(),
+        });
+        if let ReceiverError::VersionUnsupported(_) = self {
+            json["supported"] = serde_json::json!([1]);
+        }
+        json.to_string()
+    }
+
+    /// Wraps it so it can be returned from the closures of the payjoin receiver checks.
+    pub fn into_receive_error(self) -> payjoin::receive::Error {
+        payjoin::receive::Error::Server(Box::new(self))
+    }
+
+    /// Maps an error of the payjoin receiver state machine, `default` is used for the errors
+    /// that are not ours (i.e. the original PSBT failed one of the payjoin checks).
+    pub fn from_receive_error(
+        error: payjoin::receive::Error,
+        default: fn(String) -> ReceiverError,
+    ) -> ReceiverError {
+        match error {
+            payjoin::receive::Error::Server(inner) => match inner.downcast::<ReceiverError>() {
+                Ok(error) => *error,
+                Err(inner) => ReceiverError::Unavailable(inner.to_string()),
+            },
+            error => default(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ReceiverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error_code(), self.message())
+    }
+}
+
+impl std::error::Error for ReceiverError {}
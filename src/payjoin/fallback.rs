@@ -0,0 +1,115 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use bitcoincore_rpc::{Client, RpcApi};
+use payjoin::bitcoin::{consensus::encode, OutPoint, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+
+use super::{
+    payjoin_v1::BoxError,
+    store::{load_json, now, save_json},
+};
+
+const FALLBACKS_FILE: &str = "fallbacks.json";
+
+// Every read-modify-write of the fallbacks file goes through it (server and watcher threads)
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Original tx of a payjoin proposal the receiver sent, to be broadcast if the sender disappears.
+#[derive(Debug, Serialize, Deserialize)]
+struct ScheduledFallback {
+    original_txid: Txid,
+    /// Consensus hex of the (signed) original tx
+    original_tx: String,
+    /// Txid of our proposal, only final if the sender's inputs are all segwit
+    payjoin_txid: Txid,
+    inputs: Vec<OutPoint>,
+    /// Unix timestamp after which the original tx is broadcast
+    deadline: u64,
+}
+
+fn fallbacks_path() -> PathBuf {
+    config().data_dir.join(FALLBACKS_FILE)
+}
+
+/// Persists the original tx of a proposal we just replied with, it gets broadcast if neither the
+/// payjoin nor any conflicting tx shows up before the configured delay.
+pub fn schedule(original_tx: &Transaction, payjoin_txid: Txid) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut fallbacks: Vec<ScheduledFallback> = load_json(&fallbacks_path())?;
+    let original_txid = original_tx.compute_txid();
+    if fallbacks.iter().any(|f| f.original_txid == original_txid) {
+        return Ok(());
+    }
+    let deadline = now() + config().fallback_delay.as_secs();
+    fallbacks.push(ScheduledFallback {
+        original_txid,
+        original_tx: encode::serialize_hex(original_tx),
+        payjoin_txid,
+        inputs: original_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect(),
+        deadline,
+    });
+    save_json(&fallbacks_path(), &fallbacks)?;
+    println!(
+        "[Fallback] Scheduled {} (payjoin={}) for broadcast in {:?}",
+        original_txid,
+        payjoin_txid,
+        config().fallback_delay
+    );
+    Ok(())
+}
+
+/// The original tx inputs are spent (chain or mempool) as soon as the payjoin, the original tx
+/// itself or any conflicting tx is seen, in which case there is nothing left to do.
+fn inputs_spent(bitcoind: &Client, fallback: &ScheduledFallback) -> Result<bool, BoxError> {
+    for input in &fallback.inputs {
+        if bitcoind
+            .get_tx_out(&input.txid, input.vout, Some(true))?
+            .is_none()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Goes over the scheduled fallbacks, dropping the resolved ones and broadcasting the expired ones.
+pub fn check(bitcoind: &Client) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let fallbacks: Vec<ScheduledFallback> = load_json(&fallbacks_path())?;
+    if fallbacks.is_empty() {
+        return Ok(());
+    }
+
+    let mut pending = vec![];
+    for fallback in fallbacks {
+        if inputs_spent(bitcoind, &fallback)? {
+            println!(
+                "[Fallback] {} resolved (payjoin {} or a conflicting tx was seen)",
+                fallback.original_txid, fallback.payjoin_txid
+            );
+            continue;
+        }
+        if now() < fallback.deadline {
+            pending.push(fallback);
+            continue;
+        }
+        let tx: Transaction = encode::deserialize_hex(&fallback.original_tx)?;
+        match bitcoind.send_raw_transaction(&tx) {
+            Ok(txid) => println!(
+                "[Fallback] Sender went away, broadcast original tx {}",
+                txid
+            ),
+            Err(e) => println!(
+                "[Fallback] ERROR(broadcast {}): {}, dropping it",
+                fallback.original_txid, e
+            ),
+        }
+    }
+    save_json(&fallbacks_path(), &pending)
+}
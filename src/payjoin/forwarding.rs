@@ -0,0 +1,227 @@
+use std::{path::PathBuf, str::FromStr, sync::Mutex};
+
+use bitcoincore_rpc::{Client, RpcApi};
+use payjoin::bitcoin::{
+    address::NetworkUnchecked, Address, Amount, ScriptBuf, Transaction, TxOut, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+
+use super::{
+    error::ReceiverError,
+    payjoin_v1::{is_mine, new_receiver_script, BoxError},
+    store::{load_json, now, save_json},
+};
+
+const FORWARDS_FILE: &str = "forwards.json";
+/// Payment requests nobody paid are dropped after a day (secs)
+const REQUEST_EXPIRY: u64 = 24 * 60 * 60;
+
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A creditor the receiver pays out of an incoming payjoin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Forward {
+    pub address: Address<NetworkUnchecked>,
+    pub amount: Amount,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    /// `<address>:<sats>`
+    fn from_str(value: &str) -> Result<Forward, String> {
+        let (address, sats) = value
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <address>:<sats>, got {}", value))?;
+        Ok(Forward {
+            address: address
+                .parse()
+                .map_err(|e| format!("invalid address {}: {}", address, e))?,
+            amount: sats
+                .parse::<u64>()
+                .map(Amount::from_sat)
+                .map_err(|e| format!("invalid amount (sats) {}: {}", sats, e))?,
+        })
+    }
+}
+
+/// Forwards attached to the address of a payment request (URI) the receiver handed out.
+#[derive(Debug, Serialize, Deserialize)]
+struct PaymentRequest {
+    address: Address<NetworkUnchecked>,
+    forwards: Vec<Forward>,
+    created_at: u64,
+    /// Txid of the last payjoin proposed with the forwards, only final if the sender's inputs
+    /// are all segwit
+    #[serde(default)]
+    payjoin_txid: Option<Txid>,
+}
+
+impl PaymentRequest {
+    fn is_expired(&self) -> bool {
+        now() >= self.created_at + REQUEST_EXPIRY
+    }
+
+    fn is_paid_by(&self, tx: &Transaction) -> bool {
+        let script = self.address.assume_checked_ref().script_pubkey();
+        tx.output.iter().any(|txout| txout.script_pubkey == script)
+    }
+}
+
+fn forwards_path() -> PathBuf {
+    config().data_dir.join(FORWARDS_FILE)
+}
+
+/// Makes the payjoin paying `address` settle `forwards` too: the receiver's output gets replaced
+/// by them plus a drain output of ours with what is left.
+pub fn register(address: &Address, forwards: &[Forward]) -> Result<(), BoxError> {
+    if forwards.is_empty() {
+        return Ok(());
+    }
+    for forward in forwards {
+        forward
+            .address
+            .clone()
+            .require_network(config().network)
+            .map_err(|e| format!("Forward to {:?}: {}", forward.address, e))?;
+    }
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut requests: Vec<PaymentRequest> = load_json(&forwards_path())?;
+    let address = address.as_unchecked().clone();
+    requests.retain(|request| request.address != address && !request.is_expired());
+    requests.push(PaymentRequest {
+        address,
+        forwards: forwards.to_vec(),
+        created_at: now(),
+        payjoin_txid: None,
+    });
+    save_json(&forwards_path(), &requests)?;
+    println!(
+        "[Forwarding] {} payment(s) will be forwarded from the payjoin",
+        forwards.len()
+    );
+    Ok(())
+}
+
+/// Outputs replacing ours in the payjoin when the original tx pays a payment request with
+/// forwards, with the drain script among them. None for a plain payment.
+pub fn replacement_outputs(
+    receiver: &Client,
+    original_tx: &Transaction,
+) -> Result<Option<(Vec<TxOut>, ScriptBuf)>, ReceiverError> {
+    let unavailable = |e: BoxError| ReceiverError::Unavailable(e.to_string());
+    let requests: Vec<PaymentRequest> = {
+        let _lock = FILE_LOCK
+            .lock()
+            .map_err(|e| ReceiverError::Unavailable(e.to_string()))?;
+        load_json(&forwards_path()).map_err(unavailable)?
+    };
+    let request = requests
+        .iter()
+        .find(|request| !request.is_expired() && request.is_paid_by(original_tx));
+    let Some(request) = request else {
+        return Ok(None);
+    };
+
+    let mut received = Amount::ZERO;
+    for txout in &original_tx.output {
+        if is_mine(receiver, &txout.script_pubkey)
+            .map_err(|e| ReceiverError::Unavailable(e.to_string()))?
+        {
+            received += txout.value;
+        }
+    }
+    let forwarded: Amount = request.forwards.iter().map(|forward| forward.amount).sum();
+    let drain_script = new_receiver_script(receiver)?;
+    let drain = TxOut {
+        value: received.checked_sub(forwarded).unwrap_or(Amount::ZERO),
+        script_pubkey: drain_script.clone(),
+    };
+    if drain.value < drain.script_pubkey.minimal_non_dust() {
+        return Err(ReceiverError::OriginalPsbtRejected(format!(
+            "Payment of {} doesn't cover the {} forwarded by the receiver",
+            received, forwarded
+        )));
+    }
+
+    let mut outputs: Vec<TxOut> = request
+        .forwards
+        .iter()
+        .map(|forward| TxOut {
+            value: forward.amount,
+            script_pubkey: forward.address.assume_checked_ref().script_pubkey(),
+        })
+        .collect();
+    println!(
+        "[Forwarding] Forwarding {} to {} creditor(s), {} left to us",
+        forwarded,
+        outputs.len(),
+        drain.value
+    );
+    outputs.push(drain);
+    Ok(Some((outputs, drain_script)))
+}
+
+/// True if the original tx pays a pending payment request with forwards.
+pub fn has_forwards(original_tx: &Transaction) -> Result<bool, BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let requests: Vec<PaymentRequest> = load_json(&forwards_path())?;
+    Ok(requests
+        .iter()
+        .any(|request| !request.is_expired() && request.is_paid_by(original_tx)))
+}
+
+/// Records that the payjoin `payjoin_txid` carries the forwards of the request the original tx
+/// pays. The request stays until `settle` sees that payjoin: if the original tx gets broadcast
+/// instead, nobody paid the creditors.
+pub fn propose(original_tx: &Transaction, payjoin_txid: Txid) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut requests: Vec<PaymentRequest> = load_json(&forwards_path())?;
+    let Some(request) = requests
+        .iter_mut()
+        .find(|request| !request.is_expired() && request.is_paid_by(original_tx))
+    else {
+        return Ok(());
+    };
+    request.payjoin_txid = Some(payjoin_txid);
+    save_json(&forwards_path(), &requests)
+}
+
+/// Drops the payment requests whose payjoin the `receiver` wallet has seen (mempool or chain), so
+/// the forwards aren't paid again by another payment to the same address.
+pub fn settle(receiver: &Client) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut requests: Vec<PaymentRequest> = load_json(&forwards_path())?;
+    let before = requests.len();
+    requests.retain(|request| match request.payjoin_txid {
+        Some(txid) => receiver.get_transaction(&txid, None).is_err(),
+        None => true,
+    });
+    if requests.len() != before {
+        save_json(&forwards_path(), &requests)?;
+        println!(
+            "[Forwarding] {} payment request(s) settled",
+            before - requests.len()
+        );
+    }
+    Ok(())
+}
+
+/// Drops the payment requests older than `REQUEST_EXPIRY`.
+pub fn prune() -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut requests: Vec<PaymentRequest> = load_json(&forwards_path())?;
+    let before = requests.len();
+    requests.retain(|request| !request.is_expired());
+    if requests.len() != before {
+        println!(
+            "[Forwarding] Pruned {} expired payment request(s), {} left",
+            before - requests.len(),
+            requests.len()
+        );
+        save_json(&forwards_path(), &requests)?;
+    }
+    Ok(())
+}
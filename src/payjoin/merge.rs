@@ -0,0 +1,652 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bitcoincore_rpc::{Client, RpcApi};
+use payjoin::{
+    bitcoin::{
+        absolute::LockTime, psbt::Input as PsbtInput, transaction::Version, Amount, FeeRate,
+        OutPoint, Psbt, ScriptBuf, Transaction, TxIn, TxOut,
+    },
+    receive::v2::{Receiver, WantsOutputs},
+    send::V2GetContext,
+    OhttpKeys,
+};
+use rand::seq::SliceRandom;
+
+use crate::{
+    client::get_client_balance,
+    config::config,
+    weight::{predict_psbt_weight, Signatures},
+};
+
+use super::{
+    fallback, forwarding,
+    payjoin_v1::{
+        build_original_psbt, input_from_list_unspent, is_mine, new_receiver_script, BoxError,
+    },
+    payjoin_v2::{
+        check_v2_proposal, contribute_v2_proposal, finish_send, new_session, poll_original,
+        post_original, post_proposal, v2_endpoints, V2Endpoints, V2_POLL_INTERVAL,
+        V2_SESSION_EXPIRY,
+    },
+    policy::{self, InputContribution},
+    seen_inputs,
+    sender::{build_sender, SenderParams},
+    sessions::{self, SessionState},
+};
+
+/// A sender (bitcoind wallet) paying the merging receiver.
+pub struct MergeSender {
+    pub wallet: String,
+    pub client: Client,
+}
+
+/// Where the receiver stands with one sender's payment.
+enum Received {
+    Waiting,
+    Rejected,
+    /// Original PSBT passed the receiver checks
+    Checked(WantsOutputs, Transaction),
+    /// Individual payjoin proposal posted back
+    Proposed,
+}
+
+/// One sender's payment: its v2 receiver session and the sender side of it.
+struct Payment<'a> {
+    sender: &'a MergeSender,
+    receiver_session: Receiver,
+    receiver_session_id: String,
+    sender_session_id: String,
+    original_psbt: Psbt,
+    /// Receiver output of the original PSBT
+    payee: ScriptBuf,
+    get_ctx: V2GetContext,
+    received: Received,
+}
+
+/// Several senders pay the same receiver within `window`: their original PSBTs are merged with the
+/// receiver's inputs into one transaction, every sender checks and signs it and it is broadcast
+/// once all of them did. If any sender drops out, each one gets an individual payjoin instead.
+///
+/// BIP77 has no message for a multi-party proposal and this demo has no transport for one: the
+/// merged PSBT is handed to the senders' bitcoind clients in-process, so all of them must run here.
+/// The original PSBTs and the individual proposals go through the directory.
+#[allow(clippy::too_many_arguments)]
+pub async fn do_payjoin_v2_merge(
+    senders: &[MergeSender],
+    receiver: &Client,
+    receiver_wallet: &str,
+    amount: Amount,
+    fee_rate: FeeRate,
+    sender_params: &SenderParams,
+    window: Duration,
+    drop_out: Option<&str>,
+) -> Result<(), BoxError> {
+    if senders.len() < 2 {
+        return Err("Merging payjoins needs at least 2 senders".into());
+    }
+    print_balances("before", senders, receiver)?;
+
+    let endpoints = v2_endpoints().await?;
+    let relays = &endpoints.relays;
+    let ohttp_keys = relays.fetch_ohttp_keys(&endpoints.directory).await?;
+
+    // **********************
+    // Inside the Senders:
+    // Each sender gets its own payjoin URI and posts its original PSBT to it
+    let mut payments = Vec::with_capacity(senders.len());
+    for sender in senders {
+        match start_payment(
+            sender,
+            receiver,
+            receiver_wallet,
+            &endpoints,
+            ohttp_keys.clone(),
+            amount,
+            fee_rate,
+            sender_params,
+        )
+        .await
+        {
+            Ok(payment) => payments.push(payment),
+            Err(e) => println!("[PayjoinMerge] ERROR({}): {}", sender.wallet, e),
+        }
+    }
+
+    // **********************
+    // Inside the Receiver:
+    // Receiver collects (and checks) the original PSBTs posted within the window
+    collect_originals(&mut payments, receiver, &endpoints, window).await;
+
+    let checked: Vec<usize> = (0..payments.len())
+        .filter(|i| matches!(payments[*i].received, Received::Checked(..)))
+        .collect();
+    let merged = if checked.len() < 2 {
+        Err(format!("{} original PSBT(s) to merge", checked.len()).into())
+    } else {
+        merge_and_broadcast(
+            receiver,
+            &checked.iter().map(|i| &payments[*i]).collect::<Vec<_>>(),
+            sender_params,
+            drop_out,
+        )
+    };
+
+    let merged = match merged {
+        Ok(txid) => {
+            println!(
+                "[PayjoinMerge] Merged payjoin of {} senders broadcast: {}",
+                checked.len(),
+                txid
+            );
+            true
+        }
+        Err(e) => {
+            println!(
+                "[PayjoinMerge] Merge failed ({}), falling back to individual payjoins",
+                e
+            );
+            for payment in payments.iter_mut() {
+                if let Err(e) = individual_payjoin(payment, receiver, &endpoints).await {
+                    println!(
+                        "[PayjoinMerge] ERROR(receiver, {}): {}",
+                        payment.sender.wallet, e
+                    );
+                }
+            }
+            false
+        }
+    };
+
+    for payment in &payments {
+        let in_merge = merged && matches!(payment.received, Received::Checked(..));
+        sessions::remove(&payment.receiver_session_id)?;
+        if in_merge {
+            sessions::remove(&payment.sender_session_id)?;
+            continue;
+        }
+        // **********************
+        // Inside the Sender:
+        // Polls for its individual proposal, or broadcasts the original tx
+        finish_send(
+            &payment.sender.client,
+            &payment.sender_session_id,
+            &payment.get_ctx,
+            &payment.original_psbt,
+            relays,
+        )
+        .await?;
+    }
+    fallback::check(receiver)?;
+    forwarding::settle(receiver)?;
+
+    print_balances("after", senders, receiver)
+}
+
+fn print_balances(when: &str, senders: &[MergeSender], receiver: &Client) -> Result<(), BoxError> {
+    for sender in senders {
+        println!(
+            "[PayjoinMerge] Snd({}, {}): {:?}",
+            sender.wallet,
+            when,
+            get_client_balance(&sender.client)?.to_btc()
+        );
+    }
+    println!(
+        "[PayjoinMerge] Rcv({}): {:?}",
+        when,
+        get_client_balance(receiver)?.to_btc()
+    );
+    Ok(())
+}
+
+/// Opens a receiver session for the sender, which builds its original PSBT and posts it.
+#[allow(clippy::too_many_arguments)]
+async fn start_payment<'a>(
+    sender: &'a MergeSender,
+    receiver: &Client,
+    receiver_wallet: &str,
+    endpoints: &V2Endpoints,
+    ohttp_keys: OhttpKeys,
+    amount: Amount,
+    fee_rate: FeeRate,
+    sender_params: &SenderParams,
+) -> Result<Payment<'a>, BoxError> {
+    let receiver_session = Receiver::new(
+        receiver.get_new_address(None, None)?.assume_checked(),
+        endpoints.directory.clone(),
+        ohttp_keys,
+        endpoints.relays.pick(),
+        Some(V2_SESSION_EXPIRY),
+    );
+    let receiver_session_id = format!("rcv-{}", receiver_session.id());
+    sessions::save(new_session(
+        receiver_session_id.clone(),
+        receiver_wallet,
+        endpoints,
+        SessionState::Receiver(receiver_session.clone()),
+    ))?;
+
+    let payjoin_uri = receiver_session.pj_uri_builder().amount(amount).build();
+    println!(
+        "[PayjoinMerge] URI for {}:\n{}",
+        sender.wallet,
+        payjoin_uri.to_string()
+    );
+    let payee = payjoin_uri.address.script_pubkey();
+    let original_psbt =
+        build_original_psbt(&sender.client, &payjoin_uri.address, amount, fee_rate)?;
+
+    let pj_sender = build_sender(original_psbt.clone(), payjoin_uri, sender_params)?;
+    let sender_session_id = format!(
+        "snd-{}",
+        &original_psbt.unsigned_tx.compute_txid().to_string()[..16]
+    );
+    sessions::save(new_session(
+        sender_session_id.clone(),
+        &sender.wallet,
+        endpoints,
+        SessionState::Sender {
+            sender: pj_sender.clone(),
+            original_psbt: original_psbt.to_string(),
+        },
+    ))?;
+    let get_ctx = post_original(&pj_sender, &endpoints.relays).await?;
+
+    Ok(Payment {
+        sender,
+        receiver_session,
+        receiver_session_id,
+        sender_session_id,
+        original_psbt,
+        payee,
+        get_ctx,
+        received: Received::Waiting,
+    })
+}
+
+/// Polls every receiver session until all originals are in (and checked) or the window is over.
+async fn collect_originals(
+    payments: &mut [Payment<'_>],
+    receiver: &Client,
+    endpoints: &V2Endpoints,
+    window: Duration,
+) {
+    let started = Instant::now();
+    loop {
+        for payment in payments.iter_mut() {
+            if !matches!(payment.received, Received::Waiting) {
+                continue;
+            }
+            let proposal =
+                match poll_original(&mut payment.receiver_session, &endpoints.relays).await {
+                    Ok(Some(proposal)) => proposal,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!(
+                            "[PayjoinMerge] ERROR(poll, {}): {}",
+                            payment.sender.wallet, e
+                        );
+                        continue;
+                    }
+                };
+            let original_tx = proposal.extract_tx_to_schedule_broadcast();
+            payment.received = match check_v2_proposal(proposal, receiver, None) {
+                Ok(checked) => {
+                    println!(
+                        "[PayjoinMerge] Original PSBT of {} checked",
+                        payment.sender.wallet
+                    );
+                    if let Err(e) = sessions::mark_checked(
+                        &payment.receiver_session_id,
+                        original_tx.compute_txid(),
+                    ) {
+                        println!("[PayjoinMerge] ERROR(session): {}", e);
+                    }
+                    Received::Checked(checked, original_tx)
+                }
+                Err(e) => {
+                    println!(
+                        "[PayjoinMerge] Original PSBT of {} rejected: {}",
+                        payment.sender.wallet, e
+                    );
+                    Received::Rejected
+                }
+            };
+        }
+
+        let waiting = payments
+            .iter()
+            .filter(|payment| matches!(payment.received, Received::Waiting))
+            .count();
+        if waiting == 0 || started.elapsed() >= window {
+            if waiting > 0 {
+                println!("[PayjoinMerge] {} sender(s) did not post in time", waiting);
+            }
+            return;
+        }
+        println!(
+            "[PayjoinMerge] Receiver waiting for {} original PSBT(s)...",
+            waiting
+        );
+        tokio::time::sleep(V2_POLL_INTERVAL).await;
+    }
+}
+
+/// Builds the merged PSBT, has every sender sign it, adds the receiver's signatures and broadcasts.
+fn merge_and_broadcast(
+    receiver: &Client,
+    payments: &[&Payment],
+    sender_params: &SenderParams,
+    drop_out: Option<&str>,
+) -> Result<payjoin::bitcoin::Txid, BoxError> {
+    let originals: Vec<&Transaction> = payments
+        .iter()
+        .filter_map(|payment| match &payment.received {
+            Received::Checked(_, original_tx) => Some(original_tx),
+            _ => None,
+        })
+        .collect();
+    for (payment, original) in payments.iter().zip(&originals) {
+        // The merged tx has one receiver output, forwards only go through individual payjoins
+        if forwarding::has_forwards(original)? {
+            return Err(format!(
+                "{} pays a request with forwards, which merging doesn't apply",
+                payment.sender.wallet
+            )
+            .into());
+        }
+    }
+    let merged = build_merged_psbt(
+        receiver,
+        &originals,
+        sender_params.disable_output_substitution,
+    )?;
+    println!(
+        "[PayjoinMerge] Merged PSBT: {} inputs, {} outputs",
+        merged.inputs.len(),
+        merged.outputs.len()
+    );
+    for (payment, original) in payments.iter().zip(&originals) {
+        seen_inputs::record(original, &payment.receiver_session.id().to_string())?;
+    }
+
+    let mut signed = Vec::with_capacity(payments.len());
+    for payment in payments {
+        if drop_out == Some(payment.sender.wallet.as_str()) {
+            return Err(format!("{} dropped out", payment.sender.wallet).into());
+        }
+        let psbt = sign_merged(payment, &merged, sender_params)
+            .map_err(|e| format!("{} did not sign: {}", payment.sender.wallet, e))?;
+        signed.push(psbt.to_string());
+    }
+
+    let combined = receiver.combine_psbt(&signed)?;
+    let processed = receiver
+        .wallet_process_psbt(&combined, None, None, None)?
+        .psbt;
+    let finalized = receiver
+        .finalize_psbt(&processed, Some(false))?
+        .psbt
+        .ok_or("finalizepsbt should return a PSBT")?;
+    // Fails unless every input (of every party) is signed
+    let tx = Psbt::from_str(&finalized)?.extract_tx()?;
+    receiver.send_raw_transaction(&tx)?;
+    Ok(tx.compute_txid())
+}
+
+/// Unsigned PSBT spending the inputs of all the original txs plus the receiver's (picked by its
+/// policy, as for a single payjoin), to their outputs. The receiver outputs become one, which also
+/// pays for the extra weight, so the merged tx pays at least the highest fee rate of the
+/// originals. When the senders disabled output substitution (`pjos=0`) their payments are kept as
+/// they are, and the receiver's inputs go to an output of their own.
+fn build_merged_psbt(
+    receiver: &Client,
+    originals: &[&Transaction],
+    disable_output_substitution: bool,
+) -> Result<Psbt, BoxError> {
+    let policy = &config().receiver_policy;
+    let mut inputs: Vec<(TxIn, PsbtInput)> = vec![];
+    let mut outputs: Vec<TxOut> = vec![];
+    let mut received = Amount::ZERO;
+    let mut receiver_script = None;
+    let mut fees = Amount::ZERO;
+    let mut fee_rate = FeeRate::ZERO;
+    // Original paying the highest fee rate, the merged tx pays at least as much
+    let mut highest = originals.first().ok_or("No original tx to merge")?;
+    // The senders' signatures, for the weight prediction
+    let mut finals = HashMap::new();
+    let mut lock_time = 0;
+
+    for tx in originals {
+        let mut spent = Amount::ZERO;
+        for txin in &tx.input {
+            let OutPoint { txid, vout } = txin.previous_output;
+            let prevout = receiver
+                .get_tx_out(&txid, vout, Some(true))?
+                .ok_or_else(|| {
+                    format!(
+                        "Input {} of {} is spent",
+                        txin.previous_output,
+                        tx.compute_txid()
+                    )
+                })?;
+            let witness_utxo = TxOut {
+                value: prevout.value,
+                script_pubkey: ScriptBuf::from(prevout.script_pub_key.hex),
+            };
+            spent += witness_utxo.value;
+            finals.insert(
+                txin.previous_output,
+                (txin.script_sig.clone(), txin.witness.clone()),
+            );
+            inputs.push((
+                TxIn {
+                    previous_output: txin.previous_output,
+                    sequence: txin.sequence,
+                    ..Default::default()
+                },
+                PsbtInput {
+                    witness_utxo: Some(witness_utxo),
+                    ..Default::default()
+                },
+            ));
+        }
+        let fee = spent
+            .checked_sub(tx.output.iter().map(|txout| txout.value).sum())
+            .ok_or("Original tx spends more than its inputs")?;
+        fees += fee;
+        if fee / tx.weight() > fee_rate {
+            fee_rate = fee / tx.weight();
+            highest = tx;
+        }
+        lock_time = lock_time.max(tx.lock_time.to_consensus_u32());
+
+        for txout in &tx.output {
+            if is_mine(receiver, &txout.script_pubkey)? && !disable_output_substitution {
+                received += txout.value;
+                receiver_script.get_or_insert_with(|| txout.script_pubkey.clone());
+            } else {
+                outputs.push(txout.clone());
+            }
+        }
+    }
+
+    let utxos = match policy::select_utxos(receiver, policy, highest)? {
+        // The UIH heuristics are about two outputs txs, the merged one has many: the largest
+        // candidate keeps the contribution to one input
+        InputContribution::PrivacyPick(candidates) => candidates
+            .into_iter()
+            .max_by_key(|utxo| utxo.amount)
+            .into_iter()
+            .collect(),
+        InputContribution::Inputs(utxos) => utxos,
+    };
+    for utxo in &utxos {
+        inputs.push(input_from_list_unspent(utxo));
+        received += utxo.amount;
+    }
+
+    let receiver_script = match receiver_script {
+        Some(script) if !policy.allow_output_substitution => script,
+        _ => new_receiver_script(receiver)?,
+    };
+    outputs.push(TxOut {
+        value: received,
+        script_pubkey: receiver_script.clone(),
+    });
+
+    let mut rng = rand::thread_rng();
+    inputs.shuffle(&mut rng);
+    outputs.shuffle(&mut rng);
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::from_consensus(lock_time),
+        input: inputs.iter().map(|(txin, _)| txin.clone()).collect(),
+        output: outputs,
+    };
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    for (input, (_, psbtin)) in psbt.inputs.iter_mut().zip(inputs) {
+        *input = psbtin;
+    }
+
+    // The senders' inputs weigh what they did in the originals, the receiver's are predicted
+    let mut signed = psbt.clone();
+    for (input, txin) in signed.inputs.iter_mut().zip(&signed.unsigned_tx.input) {
+        if let Some((script_sig, witness)) = finals.get(&txin.previous_output) {
+            input.final_script_sig = Some(script_sig.clone());
+            input.final_script_witness = Some(witness.clone());
+        }
+    }
+    let weight = predict_psbt_weight(&signed, Signatures::LowR)?;
+    let required_fee = fee_rate.fee_wu(weight).ok_or("Fee overflow")?;
+    if required_fee > fees {
+        let receiver_output = psbt
+            .unsigned_tx
+            .output
+            .iter_mut()
+            .find(|txout| txout.script_pubkey == receiver_script)
+            .expect("receiver output was just added");
+        receiver_output.value = receiver_output
+            .value
+            .checked_sub(required_fee - fees)
+            .ok_or("Receiver output can't pay for the merged tx fee")?;
+    }
+    println!(
+        "[PayjoinMerge] Merged tx fee: {} ({} from the senders) [fee_rate={}]",
+        fees.max(required_fee),
+        fees,
+        fee_rate
+    );
+    Ok(psbt)
+}
+
+/// What a sender checks before signing the merged PSBT: its inputs are all there (same sequence),
+/// no other input of its wallet was slipped in, and its outputs other than the payment are
+/// untouched, so it spends exactly what it did in the original tx. The payment is merged with the
+/// others' unless output substitution was disabled.
+fn sign_merged(
+    payment: &Payment,
+    merged: &Psbt,
+    sender_params: &SenderParams,
+) -> Result<Psbt, BoxError> {
+    let original = &payment.original_psbt.unsigned_tx;
+    let find_input = |txin: &TxIn| {
+        merged
+            .unsigned_tx
+            .input
+            .iter()
+            .position(|merged_in| merged_in.previous_output == txin.previous_output)
+    };
+    for txin in &original.input {
+        let index =
+            find_input(txin).ok_or_else(|| format!("Input {} is missing", txin.previous_output))?;
+        if merged.unsigned_tx.input[index].sequence != txin.sequence {
+            return Err(format!("Sequence of input {} changed", txin.previous_output).into());
+        }
+    }
+    for (txin, input) in merged.unsigned_tx.input.iter().zip(&merged.inputs) {
+        if original
+            .input
+            .iter()
+            .any(|original_in| original_in.previous_output == txin.previous_output)
+        {
+            continue;
+        }
+        let script = &input
+            .witness_utxo
+            .as_ref()
+            .ok_or_else(|| format!("Input {} has no witness UTXO", txin.previous_output))?
+            .script_pubkey;
+        if is_mine(&payment.sender.client, script)? {
+            return Err(format!(
+                "Input {} is ours but wasn't in the original tx",
+                txin.previous_output
+            )
+            .into());
+        }
+    }
+    for txout in &original.output {
+        if txout.script_pubkey == payment.payee && !sender_params.disable_output_substitution {
+            continue;
+        }
+        if !merged.unsigned_tx.output.contains(txout) {
+            return Err(format!("Output {} is missing", txout.script_pubkey).into());
+        }
+    }
+
+    let signed = payment
+        .sender
+        .client
+        .wallet_process_psbt(&merged.to_string(), None, None, None)?
+        .psbt;
+    let signed = Psbt::from_str(&signed)?;
+    for txin in &original.input {
+        let input = &signed.inputs[find_input(txin).expect("input was checked")];
+        if input.final_script_witness.is_none() && input.final_script_sig.is_none() {
+            return Err(format!("Input {} was not signed", txin.previous_output).into());
+        }
+    }
+    println!(
+        "[PayjoinMerge] {} signed the merged PSBT",
+        payment.sender.wallet
+    );
+    Ok(signed)
+}
+
+/// Regular v2 payjoin for one sender, when merging didn't work out. The receiver's contributed
+/// inputs are locked, so the next proposal doesn't pick them too.
+async fn individual_payjoin(
+    payment: &mut Payment<'_>,
+    receiver: &Client,
+    endpoints: &V2Endpoints,
+) -> Result<(), BoxError> {
+    let (checked, original_tx) = match std::mem::replace(&mut payment.received, Received::Proposed)
+    {
+        Received::Checked(checked, original_tx) => (checked, original_tx),
+        other => {
+            payment.received = other;
+            return Ok(());
+        }
+    };
+    let session_id = payment.receiver_session.id().to_string();
+    let proposal = contribute_v2_proposal(checked, receiver, &session_id, &original_tx)?;
+    let contributed: Vec<OutPoint> = proposal
+        .psbt()
+        .unsigned_tx
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .filter(|outpoint| {
+            !original_tx
+                .input
+                .iter()
+                .any(|txin| txin.previous_output == *outpoint)
+        })
+        .collect();
+    receiver.lock_unspent(&contributed)?;
+    post_proposal(proposal, &endpoints.relays).await
+}
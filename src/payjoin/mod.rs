@@ -1,3 +1,18 @@
 pub mod direct;
+pub mod directory;
+pub mod error;
+pub mod fallback;
+pub mod forwarding;
+pub mod merge;
 pub mod payjoin_v1;
 pub mod payjoin_v2;
+pub mod policy;
+pub mod relay;
+pub mod relay_pool;
+pub mod seen_inputs;
+pub mod sender;
+pub mod server;
+pub mod sessions;
+pub mod store;
+pub mod tls;
+pub mod uri;
@@ -1,52 +1,49 @@
-use bitcoincore_rpc::{json::WalletProcessPsbtResult, Client, RpcApi};
+use bitcoincore_rpc::{Client, RpcApi};
 
 use payjoin::{
-    bitcoin::{
-        self, policy::DEFAULT_MIN_RELAY_TX_FEE, psbt::Input as PsbtInput,
-        transaction::InputWeightPrediction, Address, Amount, FeeRate, Network, Psbt, TxIn, TxOut,
-        Weight,
-    },
+    bitcoin::{self, psbt::Input as PsbtInput, Address, Amount, FeeRate, Psbt, TxIn, TxOut},
     receive::{Headers, InputPair},
-    send::SenderBuilder,
-    PjUri, PjUriBuilder, Request, Uri, UriExt, Url,
+    PjUri, PjUriBuilder, Uri, UriExt,
 };
 
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::client::get_client_balance;
+use crate::{
+    accounting::verify_payjoin,
+    client::{bitcoind_client, get_client_balance, wait_for_block},
+    config::config,
+};
 
-pub type BoxError = Box<dyn std::error::Error + 'static>;
+use super::{
+    error::ReceiverError,
+    fallback,
+    forwarding::{self, Forward},
+    policy::{self, InputContribution},
+    seen_inputs,
+    sender::{build_sender, send_v1, SendOutcome, SenderParams},
+    server::{TlsIdentity, V1Server},
+};
 
-struct MockHeaders {
-    length: String,
-}
+pub type BoxError = Box<dyn std::error::Error + 'static>;
 
-impl MockHeaders {
-    fn new(length: u64) -> MockHeaders {
-        MockHeaders {
-            length: length.to_string(),
-        }
-    }
-}
-
-impl Headers for MockHeaders {
-    fn get_header(&self, key: &str) -> Option<&str> {
-        match key {
-            "content-length" => Some(&self.length),
-            "content-type" => Some("text/plain"),
-            _ => None,
-        }
-    }
-}
+const FALLBACK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const SEEN_INPUTS_PRUNE_INTERVAL: Duration = Duration::from_secs(600);
 
 pub fn build_v1_pj_uri<'a>(address: bitcoin::Address, endpoint: payjoin::Url) -> PjUri<'a> {
     PjUriBuilder::new(address, endpoint, None, None, None).build()
 }
 
-fn build_original_psbt(
+pub fn build_original_psbt(
     sender: &bitcoincore_rpc::Client,
     address: &Address,
     amount: Amount,
+    fee_rate: FeeRate,
     // pj_uri: &PjUri,
 ) -> Result<Psbt, BoxError> {
     let mut outputs = HashMap::with_capacity(1);
@@ -56,7 +53,8 @@ fn build_original_psbt(
         lock_unspent: Some(true),
         // The minimum relay feerate ensures that tests fail if the receiver would add inputs/outputs
         // that cannot be covered by the sender's additional fee contributions.
-        fee_rate: Some(Amount::from_sat(DEFAULT_MIN_RELAY_TX_FEE.into())),
+        // bitcoind expects the fee rate in sat/kvB
+        fee_rate: Some(Amount::from_sat(fee_rate.to_sat_per_vb_ceil() * 1000)),
         ..Default::default()
     };
 
@@ -80,6 +78,14 @@ fn build_original_psbt(
 pub fn input_pair_from_list_unspent(
     utxo: bitcoincore_rpc::bitcoincore_rpc_json::ListUnspentResultEntry,
 ) -> InputPair {
+    let (txin, psbtin) = input_from_list_unspent(&utxo);
+    InputPair::new(txin, psbtin).expect("Input pair should be valid")
+}
+
+/// Unsigned tx input and PSBT input spending a `listunspent` UTXO.
+pub fn input_from_list_unspent(
+    utxo: &bitcoincore_rpc::bitcoincore_rpc_json::ListUnspentResultEntry,
+) -> (TxIn, PsbtInput) {
     let psbtin = PsbtInput {
         // NOTE: non_witness_utxo is not necessary because bitcoin-cli always supplies
         // witness_utxo, even for non-witness inputs
@@ -98,7 +104,60 @@ pub fn input_pair_from_list_unspent(
         },
         ..Default::default()
     };
-    InputPair::new(txin, psbtin).expect("Input pair should be valid")
+    (txin, psbtin)
+}
+
+fn script_to_address(script: &bitcoin::Script) -> Result<Address, ReceiverError> {
+    Address::from_script(script, config().network).map_err(|e| {
+        ReceiverError::OriginalPsbtRejected(format!("Unsupported script ({}): {}", script, e))
+    })
+}
+
+pub fn new_receiver_script(
+    receiver: &bitcoincore_rpc::Client,
+) -> Result<bitcoin::ScriptBuf, ReceiverError> {
+    Ok(receiver
+        .get_new_address(None, None)
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()))?
+        .assume_checked()
+        .script_pubkey())
+}
+
+pub fn is_mine(
+    receiver: &bitcoincore_rpc::Client,
+    script: &bitcoin::Script,
+) -> Result<bool, payjoin::receive::Error> {
+    let address = script_to_address(script).map_err(ReceiverError::into_receive_error)?;
+    receiver
+        .get_address_info(&address)
+        .map(|info| info.is_mine.unwrap_or(false))
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()).into_receive_error())
+}
+
+pub fn can_broadcast(
+    receiver: &bitcoincore_rpc::Client,
+    tx: &bitcoin::Transaction,
+) -> Result<bool, payjoin::receive::Error> {
+    let results = receiver
+        .test_mempool_accept(&[bitcoin::consensus::encode::serialize_hex(tx)])
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()).into_receive_error())?;
+    Ok(results.first().map(|res| res.allowed).unwrap_or(false))
+}
+
+pub fn process_psbt(
+    receiver: &bitcoincore_rpc::Client,
+    psbt: &Psbt,
+) -> Result<Psbt, payjoin::receive::Error> {
+    let processed = receiver
+        .wallet_process_psbt(
+            &psbt.to_string(),
+            None,
+            None,
+            Some(true), // check that the receiver properly clears keypaths
+        )
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()).into_receive_error())?;
+    Psbt::from_str(&processed.psbt)
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()).into_receive_error())
 }
 
 fn handle_proposal(
@@ -107,106 +166,131 @@ fn handle_proposal(
     custom_outputs: Option<Vec<TxOut>>,
     drain_script: Option<&bitcoin::Script>,
     custom_inputs: Option<Vec<InputPair>>,
-) -> Result<payjoin::receive::PayjoinProposal, BoxError> {
+) -> Result<payjoin::receive::PayjoinProposal, ReceiverError> {
+    let rejected = ReceiverError::OriginalPsbtRejected;
+    let policy = &config().receiver_policy;
+
     // in a payment processor where the sender could go offline, this is where you schedule to broadcast the original_tx
-    let _to_broadcast_in_failure_case = proposal.extract_tx_to_schedule_broadcast();
+    let to_broadcast_in_failure_case = proposal.extract_tx_to_schedule_broadcast();
+    // v1 has no session, the original tx identifies the request
+    let session_id = to_broadcast_in_failure_case.compute_txid().to_string();
 
     // Receive Check 1: Can Broadcast
-    let proposal = proposal.check_broadcast_suitability(None, |tx| {
-        Ok(receiver
-            .test_mempool_accept(&[bitcoin::consensus::encode::serialize_hex(&tx)])
-            .unwrap()
-            .first()
-            .unwrap()
-            .allowed)
-    })?;
+    let proposal = proposal
+        .check_broadcast_suitability(policy.min_original_fee_rate, |tx| {
+            can_broadcast(receiver, tx)
+        })
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?;
 
     // Receive Check 2: receiver can't sign for proposal inputs
-    let proposal = proposal.check_inputs_not_owned(|input| {
-        let address = bitcoin::Address::from_script(input, Network::Signet).unwrap();
-        Ok(receiver
-            .get_address_info(&address)
-            .map(|info| info.is_mine.unwrap_or(false))
-            .unwrap())
-    })?;
+    let proposal = proposal
+        .check_inputs_not_owned(|input| is_mine(receiver, input))
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?;
 
     // Receive Check 3: have we seen this input before? More of a check for non-interactive i.e. payment processor receivers.
     let payjoin = proposal
-        .check_no_inputs_seen_before(|_| Ok(false))?
-        .identify_receiver_outputs(|output_script| {
-            let address = bitcoin::Address::from_script(output_script, Network::Signet).unwrap();
-            Ok(receiver
-                .get_address_info(&address)
-                .map(|info| info.is_mine.unwrap_or(false))
-                .unwrap())
-        })?;
-
-    let payjoin = match custom_outputs {
-        Some(txos) => payjoin.replace_receiver_outputs(
-            txos,
-            drain_script.expect("drain_script should be provided with custom_outputs"),
-        )?,
-        None => payjoin.substitute_receiver_script(
-            &receiver
-                .get_new_address(None, None)?
-                .assume_checked()
-                .script_pubkey(),
-        )?,
+        .check_no_inputs_seen_before(|outpoint| {
+            seen_inputs::check(outpoint)
+                .map_err(|e| ReceiverError::Unavailable(e.to_string()).into_receive_error())
+        })
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?
+        .identify_receiver_outputs(|output_script| is_mine(receiver, output_script))
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?;
+
+    // Outputs given by the caller, or the forwards of the payment request being paid
+    let (custom_outputs, drain_script) = match custom_outputs {
+        Some(txos) => (Some(txos), drain_script.map(|script| script.to_owned())),
+        None => match forwarding::replacement_outputs(receiver, &to_broadcast_in_failure_case)? {
+            Some((txos, drain_script)) => (Some(txos), Some(drain_script)),
+            None => (None, None),
+        },
+    };
+
+    let substitute = if !policy.allow_output_substitution {
+        println!("[PayjoinV1] Output substitution disabled by the receiver policy");
+        false
+    } else if payjoin.is_output_substitution_disabled() {
+        println!("[PayjoinV1] Output substitution disabled by the sender");
+        false
+    } else {
+        true
+    };
+    let forwarded = substitute && custom_outputs.is_some();
+    let payjoin = if !substitute {
+        if custom_outputs.is_some() {
+            println!("[PayjoinV1] Not forwarding, the payment is received as is");
+        }
+        payjoin
+    } else {
+        match custom_outputs {
+            Some(txos) => {
+                let drain_script = drain_script.as_deref().ok_or_else(|| {
+                    ReceiverError::Unavailable(
+                        "drain_script should be provided with custom_outputs".to_string(),
+                    )
+                })?;
+                payjoin.replace_receiver_outputs(txos, drain_script)
+            }
+            None => payjoin.substitute_receiver_script(&new_receiver_script(receiver)?),
+        }
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to substitute outputs: {:?}", e)))?
     }
     .commit_outputs();
 
     let inputs = match custom_inputs {
         Some(inputs) => inputs,
-        None => {
-            let candidate_inputs = receiver
-                .list_unspent(None, None, None, None, None)?
-                .into_iter()
-                .map(input_pair_from_list_unspent);
-            let selected_input = payjoin
-                .try_preserving_privacy(candidate_inputs)
-                .map_err(|e| format!("Failed to make privacy preserving selection: {:?}", e))?;
-            vec![selected_input]
-        }
+        None => match policy::select_inputs(receiver, policy, &to_broadcast_in_failure_case)? {
+            InputContribution::PrivacyPick(candidates) => {
+                let selected_input = payjoin.try_preserving_privacy(candidates).map_err(|e| {
+                    ReceiverError::Unavailable(format!(
+                        "Failed to make privacy preserving selection: {:?}",
+                        e
+                    ))
+                })?;
+                vec![selected_input]
+            }
+            InputContribution::Inputs(inputs) => inputs,
+        },
     };
     let payjoin = payjoin
         .contribute_inputs(inputs)
-        .map_err(|e| format!("Failed to contribute inputs: {:?}", e))?
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to contribute inputs: {:?}", e)))?
         .commit_inputs();
 
-    let payjoin_proposal = payjoin.finalize_proposal(
-        |psbt: &Psbt| {
-            Ok(receiver
-                .wallet_process_psbt(
-                    &psbt.to_string(),
-                    None,
-                    None,
-                    Some(true), // check that the receiver properly clears keypaths
-                )
-                .map(|res: WalletProcessPsbtResult| {
-                    Psbt::from_str(&res.psbt).expect("psbt should be valid")
-                })
-                .unwrap())
-        },
-        Some(FeeRate::BROADCAST_MIN),
-        Some(FeeRate::from_sat_per_vb_unchecked(2)).unwrap(),
-    )?;
+    let payjoin_proposal = payjoin
+        .finalize_proposal(
+            |psbt: &Psbt| process_psbt(receiver, psbt),
+            policy
+                .min_proposal_fee_rate
+                .or(Some(FeeRate::BROADCAST_MIN)),
+            policy.max_fee_rate,
+        )
+        .map_err(|e| ReceiverError::from_receive_error(e, ReceiverError::NotEnoughMoney))?;
+
+    seen_inputs::record(&to_broadcast_in_failure_case, &session_id)
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to record inputs: {}", e)))?;
+    let payjoin_txid = payjoin_proposal.psbt().unsigned_tx.compute_txid();
+    fallback::schedule(&to_broadcast_in_failure_case, payjoin_txid)
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to schedule fallback: {}", e)))?;
+    if forwarded {
+        forwarding::propose(&to_broadcast_in_failure_case, payjoin_txid)
+            .map_err(|e| ReceiverError::Unavailable(format!("Failed to record forwards: {}", e)))?;
+    }
     Ok(payjoin_proposal)
 }
 
-fn handle_v1_pj_request(
-    req: Request,
+pub fn handle_v1_pj_request(
+    body: &[u8],
+    query: &str,
+    headers: impl Headers,
     receiver: &bitcoincore_rpc::Client,
     custom_outputs: Option<Vec<TxOut>>,
     drain_script: Option<&bitcoin::Script>,
     custom_inputs: Option<Vec<InputPair>>,
-) -> Result<String, BoxError> {
-    // Receiver receive payjoin proposal, IRL it will be an HTTP request (over ssl or onion)
-    let headers = MockHeaders::new(req.body.len() as u64);
-    let proposal = payjoin::receive::UncheckedProposal::from_request(
-        req.body.as_slice(),
-        req.url.query().unwrap_or(""),
-        headers,
-    )?;
+) -> Result<String, ReceiverError> {
+    // Receiver receive payjoin proposal from the HTTP(S) request
+    let proposal = payjoin::receive::UncheckedProposal::from_request(body, query, headers)
+        .map_err(|e| ReceiverError::from_receive_error(e, ReceiverError::OriginalPsbtRejected))?;
 
     let proposal = handle_proposal(
         proposal,
@@ -216,7 +300,10 @@ fn handle_v1_pj_request(
         custom_inputs,
     )?;
 
-    assert!(!proposal.is_output_substitution_disabled());
+    println!(
+        "[PayjoinV1] Output substitution disabled by the sender: {}",
+        proposal.is_output_substitution_disabled()
+    );
     let psbt = proposal.psbt();
     println!(
         "[PayjoinV1] Receiver's Payjoin proposal PSBT(inputs.len): {:#?}",
@@ -229,71 +316,97 @@ fn handle_v1_pj_request(
     Ok(psbt.to_string())
 }
 
-fn extract_pj_tx(
-    sender: &bitcoincore_rpc::Client,
-    psbt: Psbt,
-) -> Result<bitcoin::Transaction, Box<dyn std::error::Error>> {
-    let payjoin_psbt = sender
-        .wallet_process_psbt(&psbt.to_string(), None, None, None)?
-        .psbt;
-    let payjoin_psbt = sender
-        .finalize_psbt(&payjoin_psbt, Some(false))?
-        .psbt
-        .expect("should contain a PSBT");
-    let payjoin_psbt = Psbt::from_str(&payjoin_psbt)?;
-    println!(
-        "[PayjoinV1] Final Payjoin PSBT(inputs.len): {:#?}",
-        &payjoin_psbt.inputs.len()
-    );
-    println!(
-        "[PayjoinV1] Final Payjoin PSBT(outputs.len): {:#?}",
-        &payjoin_psbt.outputs.len()
-    );
-    Ok(payjoin_psbt.extract_tx()?)
+/// Runs the BIP78 endpoint for merchants, printing a payjoin URI that points to it: at
+/// `public_url`, or at the address the server listens on.
+pub fn serve_v1(
+    bind: SocketAddr,
+    public_url: Option<payjoin::Url>,
+    receiver_wallet: &str,
+    amount: Option<Amount>,
+    tls: Option<TlsIdentity>,
+    forwards: &[Forward],
+) -> Result<(), BoxError> {
+    if public_url.is_none() && bind.ip().is_unspecified() {
+        return Err(format!(
+            "Listening on {} (all interfaces), pass --public-url with the endpoint senders reach",
+            bind
+        )
+        .into());
+    }
+    let receiver = bitcoind_client(receiver_wallet)?;
+    let server = V1Server::start(bind, receiver_wallet, tls)?;
+
+    let address = receiver.get_new_address(None, None)?.assume_checked();
+    forwarding::register(&address, forwards)?;
+    let endpoint = public_url.unwrap_or_else(|| server.url().clone());
+    let mut pj_uri = build_v1_pj_uri(address, endpoint);
+    pj_uri.amount = amount;
+    println!("[PayjoinV1] URI:\n{}", pj_uri);
+
+    spawn_housekeeping(receiver_wallet.to_string());
+    server.wait();
+    Ok(())
 }
 
-/// Simplified input weight predictions for a fully-signed transaction
-fn predicted_tx_weight(tx: &bitcoin::Transaction) -> Weight {
-    let input_weight_predictions = tx.input.iter().map(|txin| {
-        // See https://bitcoin.stackexchange.com/a/107873
-        match (txin.script_sig.is_empty(), txin.witness.is_empty()) {
-            // witness is empty: legacy input
-            (false, true) => InputWeightPrediction::P2PKH_COMPRESSED_MAX,
-            // script sig is empty: native segwit input
-            (true, false) => match txin.witness.len() {
-                // <signature>
-                1 => InputWeightPrediction::P2TR_KEY_DEFAULT_SIGHASH,
-                // <signature> <public_key>
-                2 => InputWeightPrediction::P2WPKH_MAX,
-                _ => panic!("unsupported witness"),
-            },
-            // neither are empty: nested segwit (p2wpkh-in-p2sh) input
-            (false, false) => InputWeightPrediction::from_slice(23, &[72, 33]),
-            _ => panic!("one of script_sig or witness should be non-empty"),
+/// Broadcasts the original tx of the proposals whose sender went away, settles the payment requests
+/// whose payjoin is out and prunes the seen inputs that got spent and the expired payment
+/// requests, with its own client of the `receiver_wallet`.
+fn spawn_housekeeping(receiver_wallet: String) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let receiver = match bitcoind_client(&receiver_wallet) {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                println!("[PayjoinV1] ERROR(bitcoind_client): {}", e);
+                return;
+            }
+        };
+        let mut next_prune = Instant::now();
+        loop {
+            if let Err(e) = fallback::check(&receiver) {
+                println!("[PayjoinV1] ERROR(fallback::check): {}", e);
+            }
+            if let Err(e) = forwarding::settle(&receiver) {
+                println!("[PayjoinV1] ERROR(forwarding::settle): {}", e);
+            }
+            if Instant::now() >= next_prune {
+                if let Err(e) = seen_inputs::prune(&receiver) {
+                    println!("[PayjoinV1] ERROR(seen_inputs::prune): {}", e);
+                }
+                if let Err(e) = forwarding::prune() {
+                    println!("[PayjoinV1] ERROR(forwarding::prune): {}", e);
+                }
+                next_prune = Instant::now() + SEEN_INPUTS_PRUNE_INTERVAL;
+            }
+            thread::sleep(FALLBACK_CHECK_INTERVAL);
         }
-    });
-    bitcoin::transaction::predict_weight(input_weight_predictions, tx.script_pubkey_lens())
+    })
 }
 
-pub fn do_payjoin_v1(
+#[allow(clippy::too_many_arguments)]
+pub async fn do_payjoin_v1(
+    miner: &Client,
     sender: &Client,
     receiver: &Client,
+    receiver_wallet: &str,
     amount: Amount,
-    is_p2pkh: bool,
+    fee_rate: FeeRate,
+    sender_params: &SenderParams,
+    forwards: &[Forward],
 ) -> Result<(), BoxError> {
-    println!(
-        "[PayjoinV1] Snd(before): {:?}",
-        get_client_balance(sender)?.to_btc()
-    );
-    println!(
-        "[PayjoinV1] Rcv(before): {:?}",
-        get_client_balance(receiver)?.to_btc()
-    );
-
-    // Receiver creates the payjoin URI
+    let balances_before = [get_client_balance(sender)?, get_client_balance(receiver)?];
+    println!("[PayjoinV1] Snd(before): {:?}", balances_before[0].to_btc());
+    println!("[PayjoinV1] Rcv(before): {:?}", balances_before[1].to_btc());
+
+    // Receiver runs its BIP78 endpoint (localhost, self-signed TLS) and creates the payjoin URI
+    let tls = TlsIdentity::self_signed()?;
+    let server = V1Server::start(
+        SocketAddr::from(([127, 0, 0, 1], 0)),
+        receiver_wallet,
+        Some(tls.clone()),
+    )?;
     let pj_receiver_address = receiver.get_new_address(None, None)?.assume_checked();
-    let endpoint = Url::parse("https://example.com")?;
-    let mut pj_uri = build_v1_pj_uri(pj_receiver_address.clone(), endpoint);
+    forwarding::register(&pj_receiver_address, forwards)?;
+    let mut pj_uri = build_v1_pj_uri(pj_receiver_address.clone(), server.url().clone());
     pj_uri.amount = Some(amount);
 
     // **********************
@@ -305,53 +418,34 @@ pub fn do_payjoin_v1(
         .check_pj_supported()
         .map_err(|e| e.to_string())?;
 
-    let psbt = build_original_psbt(&sender, &pj_receiver_address, amount)?;
+    let original_psbt = build_original_psbt(sender, &pj_receiver_address, amount, fee_rate)?;
     println!(
         "[PayjoinV1] Sender's Payjoin proposal PSBT(inputs.len): {:#?}",
-        &psbt.inputs.len()
+        &original_psbt.inputs.len()
     );
     println!(
         "[PayjoinV1] Sender's Payjoin proposal PSBT(outputs.len): {:#?}",
-        &psbt.outputs.len()
+        &original_psbt.outputs.len()
     );
 
-    let (req, ctx) = SenderBuilder::from_psbt_and_uri(psbt, uri)?
-        .build_with_additional_fee(Amount::from_sat(10000), None, FeeRate::ZERO, false)?
-        .extract_v1()?;
-
-    // **********************
-    // Inside the Receiver:
-    // this data would transit from one party to another over the network in production
-    let response = handle_v1_pj_request(req, &receiver, None, None, None)?;
-    // this response would be returned as http response to the sender
+    let (req, ctx) = build_sender(original_psbt.clone(), uri, sender_params)?.extract_v1()?;
 
     // **********************
     // Inside the Sender:
-    // Sender checks, signs, finalizes, extracts, and broadcasts
-    let checked_payjoin_proposal_psbt = ctx.process_response(&mut response.as_bytes())?;
-    let payjoin_tx = extract_pj_tx(&sender, checked_payjoin_proposal_psbt)?;
-    sender.send_raw_transaction(&payjoin_tx)?;
-
-    // Check resulting transaction and balances
-    let mut predicted_tx_weight = predicted_tx_weight(&payjoin_tx);
-    if is_p2pkh {
-        // HACK:
-        // bitcoin-cli always grinds signatures to save 1 byte (4WU) and simplify fee
-        // estimates. This results in the original PSBT having a fee of 219 sats
-        // instead of the "worst case" 220 sats assuming a maximum-size signature.
-        // Note that this also affects weight predictions for segwit inputs, but the
-        // resulting signatures are only 1WU smaller (.25 bytes) and therefore don't
-        // affect our weight predictions for the original sender inputs.
-        predicted_tx_weight -= Weight::from_non_witness_data_size(1);
+    // Sender posts the original PSBT to the receiver's endpoint, checks, signs, finalizes and
+    // broadcasts the payjoin (or the original tx if anything goes wrong)
+    let outcome = send_v1(sender, &original_psbt, req, ctx, Some(&tls.cert_pem)).await?;
+    drop(server);
+    println!("[PayjoinV1] Broadcast {}", outcome.tx().compute_txid());
+    if matches!(outcome, SendOutcome::Fallback(_)) {
+        println!("[PayjoinV1] Receiver did not payjoin, the original tx was broadcast");
     }
+    // Receiver: the payjoin (or the original tx) is out, nothing left to fall back to
+    fallback::check(receiver)?;
+    forwarding::settle(receiver)?;
+    seen_inputs::prune(receiver)?;
 
-    let network_fees = predicted_tx_weight * FeeRate::BROADCAST_MIN;
-
-    // assert_eq!(payjoin_tx.input.len(), 2);
-    // assert_eq!(payjoin_tx.output.len(), 2);
-    // assert_eq!(receiver.get_balances()?.mine.untrusted_pending, amount + receiver_initial_balance);
-    // assert_eq!(sender.get_balances()?.mine.untrusted_pending, sender_initial_balance - amount - network_fees);
-
+    wait_for_block(miner, 1)?;
     println!(
         "[PayjoinV1] Snd(after): {:?}",
         get_client_balance(sender)?.to_btc()
@@ -360,7 +454,14 @@ pub fn do_payjoin_v1(
         "[PayjoinV1] Rcv(after): {:?}",
         get_client_balance(receiver)?.to_btc()
     );
-    println!("[PayjoinV1] Fee     : {}", network_fees);
-
-    Ok(())
+    verify_payjoin(
+        sender,
+        receiver,
+        balances_before,
+        &outcome,
+        amount,
+        original_psbt.fee()? + sender_params.max_additional_fee,
+        forwards,
+        sender_params.disable_output_substitution,
+    )
 }
@@ -1,55 +1,287 @@
 use bitcoincore_rpc::bitcoin::psbt::Psbt;
 use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::bitcoin::FeeRate;
+use bitcoincore_rpc::bitcoin::Transaction;
 use bitcoincore_rpc::bitcoin::Txid;
 use bitcoincore_rpc::{Client, RpcApi};
 
-use payjoin::io::fetch_ohttp_keys;
-use payjoin::send::SenderBuilder;
+use payjoin::receive::v2::{PayjoinProposal, Receiver, UncheckedProposal, WantsOutputs};
+use payjoin::send::{Sender, V2GetContext};
 use url::Url;
 
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use crate::client::get_client_balance;
+use crate::{
+    accounting::verify_payjoin,
+    client::{bitcoind_client, get_client_balance, wait_for_block},
+    config::config,
+};
 
-fn https_agent() -> reqwest::Client {
-    let https = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .expect("failed to build https client");
-    https
+use super::{
+    directory::LocalDirectory,
+    error::ReceiverError,
+    fallback,
+    forwarding::{self, Forward},
+    payjoin_v1::{can_broadcast, is_mine, new_receiver_script, process_psbt},
+    policy::{self, InputContribution},
+    relay::LocalRelay,
+    relay_pool::RelayPool,
+    seen_inputs,
+    sender::{broadcast_or_fallback, build_sender, SendOutcome, SenderParams},
+    server::TlsIdentity,
+    sessions::{self, SessionState, V2Session},
+    store::now,
+};
+
+pub const V2_POLL_INTERVAL: Duration = Duration::from_secs(1);
+pub const V2_SESSION_EXPIRY: Duration = Duration::from_secs(600);
+
+/// Local OHTTP relays started when none is configured, requests are spread over both.
+const LOCAL_RELAYS: usize = 2;
+
+/// Directory and OHTTP relays the v2 flow talks to.
+pub struct V2Endpoints {
+    pub directory: Url,
+    pub relays: RelayPool,
+    // Local stand-ins, they stop when dropped
+    _local: Option<(Vec<LocalRelay>, LocalDirectory)>,
+}
+
+impl V2Endpoints {
+    fn is_local(&self) -> bool {
+        self._local.is_some()
+    }
+}
+
+/// The configured directory and relay, or local stand-ins so v2 also runs without internet access.
+pub async fn v2_endpoints() -> Result<V2Endpoints, Box<dyn std::error::Error>> {
+    let config = config();
+    if let Some(directory) = &config.v2_directory {
+        return Ok(V2Endpoints {
+            directory: directory.clone(),
+            relays: RelayPool::new(config.v2_ohttp_relays.clone(), None)?,
+            _local: None,
+        });
+    }
+
+    println!("[PayjoinV2] No v2 directory/relays configured, starting local ones");
+    start_local_endpoints(0, &[0; LOCAL_RELAYS]).await
+}
+
+async fn start_local_endpoints(
+    directory_port: u16,
+    relay_ports: &[u16],
+) -> Result<V2Endpoints, Box<dyn std::error::Error>> {
+    let tls = TlsIdentity::self_signed()?;
+    let directory =
+        LocalDirectory::start(SocketAddr::from(([127, 0, 0, 1], directory_port)), &tls)?;
+    let mut relays = Vec::with_capacity(relay_ports.len());
+    for port in relay_ports {
+        relays.push(
+            LocalRelay::start(
+                SocketAddr::from(([127, 0, 0, 1], *port)),
+                directory.url().clone(),
+                &tls.cert_pem,
+            )
+            .await?,
+        );
+    }
+    Ok(V2Endpoints {
+        directory: directory.url().clone(),
+        relays: RelayPool::new(
+            relays.iter().map(|relay| relay.url().clone()).collect(),
+            Some(tls.cert_pem),
+        )?,
+        _local: Some((relays, directory)),
+    })
+}
+
+/// Endpoints a persisted session was created with, the local stand-ins are restarted on the same
+/// ports (the local directory keeps its OHTTP key and mailboxes in the data dir).
+async fn session_endpoints(session: &V2Session) -> Result<V2Endpoints, Box<dyn std::error::Error>> {
+    if !session.local {
+        return Ok(V2Endpoints {
+            directory: session.directory.clone(),
+            relays: RelayPool::new(session.ohttp_relays.clone(), None)?,
+            _local: None,
+        });
+    }
+    let port = |url: &Url| url.port().ok_or_else(|| format!("No port in {}", url));
+    let relay_ports = session
+        .ohttp_relays
+        .iter()
+        .map(port)
+        .collect::<Result<Vec<_>, _>>()?;
+    start_local_endpoints(port(&session.directory)?, &relay_ports).await
+}
+
+pub fn new_session(
+    id: String,
+    wallet: &str,
+    endpoints: &V2Endpoints,
+    state: SessionState,
+) -> V2Session {
+    V2Session {
+        id,
+        wallet: wallet.to_string(),
+        created_at: now(),
+        expiry: now() + V2_SESSION_EXPIRY.as_secs(),
+        directory: endpoints.directory.clone(),
+        ohttp_relays: endpoints.relays.relays().to_vec(),
+        local: endpoints.is_local(),
+        checked_original: None,
+        state,
+    }
+}
+
+/// The receiver checks of the original PSBT (broadcastable, no inputs of ours, none seen before).
+/// `checked_before` is the original this session already checked before being resumed, its
+/// inputs are ours to see again.
+pub fn check_v2_proposal(
+    proposal: UncheckedProposal,
+    receiver: &Client,
+    checked_before: Option<Txid>,
+) -> Result<WantsOutputs, ReceiverError> {
+    let rejected = ReceiverError::OriginalPsbtRejected;
+    let policy = &config().receiver_policy;
+    let resumed =
+        checked_before == Some(proposal.extract_tx_to_schedule_broadcast().compute_txid());
+
+    proposal
+        .check_broadcast_suitability(policy.min_original_fee_rate, |tx| {
+            can_broadcast(receiver, tx)
+        })
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?
+        .check_inputs_not_owned(|input| is_mine(receiver, input))
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?
+        .check_no_inputs_seen_before(|outpoint| {
+            if resumed {
+                return Ok(false);
+            }
+            seen_inputs::check(outpoint)
+                .map_err(|e| ReceiverError::Unavailable(e.to_string()).into_receive_error())
+        })
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))?
+        .identify_receiver_outputs(|output_script| is_mine(receiver, output_script))
+        .map_err(|e| ReceiverError::from_receive_error(e, rejected))
 }
 
+/// Outputs, inputs and signature of the receiver, following its policy. The original tx's inputs
+/// are recorded as seen in `session_id`, and it gets scheduled for broadcast in case the sender
+/// never broadcasts the payjoin.
+pub fn contribute_v2_proposal(
+    payjoin: WantsOutputs,
+    receiver: &Client,
+    session_id: &str,
+    to_broadcast_in_failure_case: &Transaction,
+) -> Result<PayjoinProposal, ReceiverError> {
+    let policy = &config().receiver_policy;
+
+    let forwards = forwarding::replacement_outputs(receiver, to_broadcast_in_failure_case)?;
+    let substitute = if !policy.allow_output_substitution {
+        println!("[PayjoinV2] Output substitution disabled by the receiver policy");
+        false
+    } else if payjoin.is_output_substitution_disabled() {
+        println!("[PayjoinV2] Output substitution disabled by the sender");
+        false
+    } else {
+        true
+    };
+    let forwarded = substitute && forwards.is_some();
+    let payjoin = if substitute {
+        match forwards {
+            Some((txos, drain_script)) => payjoin.replace_receiver_outputs(txos, &drain_script),
+            None => payjoin.substitute_receiver_script(&new_receiver_script(receiver)?),
+        }
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to substitute outputs: {:?}", e)))?
+    } else {
+        if forwards.is_some() {
+            println!("[PayjoinV2] Not forwarding, the payment is received as is");
+        }
+        payjoin
+    }
+    .commit_outputs();
+
+    let inputs = match policy::select_inputs(receiver, policy, to_broadcast_in_failure_case)? {
+        InputContribution::PrivacyPick(candidates) => {
+            vec![payjoin.try_preserving_privacy(candidates).map_err(|e| {
+                ReceiverError::Unavailable(format!(
+                    "Failed to make privacy preserving selection: {:?}",
+                    e
+                ))
+            })?]
+        }
+        InputContribution::Inputs(inputs) => inputs,
+    };
+
+    let payjoin_proposal = payjoin
+        .contribute_inputs(inputs)
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to contribute inputs: {:?}", e)))?
+        .commit_inputs()
+        .finalize_proposal(
+            |psbt: &Psbt| process_psbt(receiver, psbt),
+            policy
+                .min_proposal_fee_rate
+                .or(Some(FeeRate::BROADCAST_MIN)),
+            policy.max_fee_rate,
+        )
+        .map_err(|e| ReceiverError::from_receive_error(e, ReceiverError::NotEnoughMoney))?;
+
+    seen_inputs::record(to_broadcast_in_failure_case, session_id)
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to record inputs: {}", e)))?;
+    let payjoin_txid = payjoin_proposal.psbt().unsigned_tx.compute_txid();
+    fallback::schedule(to_broadcast_in_failure_case, payjoin_txid)
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to schedule fallback: {}", e)))?;
+    if forwarded {
+        forwarding::propose(to_broadcast_in_failure_case, payjoin_txid)
+            .map_err(|e| ReceiverError::Unavailable(format!("Failed to record forwards: {}", e)))?;
+    }
+    Ok(payjoin_proposal)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn do_payjoin_v2(
+    miner: &Client,
     sender: &Client,
     receiver: &Client,
+    sender_wallet: &str,
+    receiver_wallet: &str,
     amount: Amount,
+    fee_rate: FeeRate,
+    sender_params: &SenderParams,
+    forwards: &[Forward],
 ) -> Result<Txid, Box<dyn std::error::Error>> {
-    println!(
-        "[PayjoinV2] Snd(before): {:?}",
-        get_client_balance(sender)?.to_btc()
-    );
-    println!(
-        "[PayjoinV2] Rcv(before): {:?}",
-        get_client_balance(receiver)?.to_btc()
-    );
+    let balances_before = [get_client_balance(sender)?, get_client_balance(receiver)?];
+    println!("[PayjoinV2] Snd(before): {:?}", balances_before[0].to_btc());
+    println!("[PayjoinV2] Rcv(before): {:?}", balances_before[1].to_btc());
 
-    let ohttp_relay = Url::parse("https://pj.bobspacebkk.com")?;
-    let directory = Url::parse("https://payjo.in")?;
+    let endpoints = v2_endpoints().await?;
+    let (relays, directory) = (endpoints.relays.clone(), endpoints.directory.clone());
 
     let receiver_address = receiver.get_new_address(None, None)?.assume_checked();
 
     // Preparing Payjoin URI
-    let ohttp_keys = fetch_ohttp_keys(ohttp_relay.clone(), directory.clone()).await?;
-    let reveiver_session = payjoin::receive::v2::Receiver::new(
+    let ohttp_keys = relays.fetch_ohttp_keys(&directory).await?;
+    let mut reveiver_session = Receiver::new(
         receiver_address,
         directory.clone(),
         ohttp_keys,
-        ohttp_relay.clone(),
-        Some(std::time::Duration::from_secs(600)),
+        relays.pick(),
+        Some(V2_SESSION_EXPIRY),
     );
+    let receiver_session_id = format!("rcv-{}", reveiver_session.id());
+    sessions::save(new_session(
+        receiver_session_id.clone(),
+        receiver_wallet,
+        &endpoints,
+        SessionState::Receiver(reveiver_session.clone()),
+    ))?;
 
     let payjoin_uri = reveiver_session.pj_uri_builder().amount(amount).build();
+    forwarding::register(&payjoin_uri.address, forwards)?;
 
     println!("[PayjoinV2] URI:\n{}", payjoin_uri.to_string());
 
@@ -60,7 +292,8 @@ pub async fn do_payjoin_v2(
     outputs.insert(receiver_address.to_string(), amount_to_send);
     let options = bitcoincore_rpc::json::WalletCreateFundedPsbtOptions {
         lock_unspent: Some(false),
-        fee_rate: Some(bitcoincore_rpc::bitcoin::Amount::from_sat(10000)),
+        // bitcoind expects the fee rate in sat/kvB
+        fee_rate: Some(Amount::from_sat(fee_rate.to_sat_per_vb_ceil() * 1000)),
         ..Default::default()
     };
 
@@ -78,44 +311,290 @@ pub async fn do_payjoin_v2(
 
     let psbt = Psbt::from_str(&psbt)?;
 
-    let (req, send_ctx) = SenderBuilder::from_psbt_and_uri(psbt.clone(), payjoin_uri)?
-        .build_with_additional_fee(
-            bitcoincore_rpc::bitcoin::Amount::from_sat(1),
-            None,
-            bitcoincore_rpc::bitcoin::FeeRate::MIN,
-            true,
-        )?
-        .extract_v2(ohttp_relay)?;
-
-    let res = https_agent()
-        .post(req.url)
-        .body(req.body)
-        .header("Content-Type", payjoin::V2_REQ_CONTENT_TYPE)
-        .send()
-        .await?;
+    let pj_sender = build_sender(psbt.clone(), payjoin_uri, sender_params)?;
+    let sender_session_id = format!("snd-{}", &psbt.unsigned_tx.compute_txid().to_string()[..16]);
+    sessions::save(new_session(
+        sender_session_id.clone(),
+        sender_wallet,
+        &endpoints,
+        SessionState::Sender {
+            sender: pj_sender.clone(),
+            original_psbt: psbt.to_string(),
+        },
+    ))?;
 
-    send_ctx.process_response(&res.bytes().await?)?;
+    // **********************
+    // Inside the Sender:
+    // Sender posts the original PSBT to the receiver's subdirectory (through the relay)
+    let get_ctx = post_original(&pj_sender, &relays).await?;
 
-    let psbt = sender
-        .wallet_process_psbt(&psbt.to_string(), None, None, None)?
-        .psbt;
+    // **********************
+    // Inside the Receiver:
+    // Receiver polls the directory, checks the original PSBT, contributes and posts its proposal
+    match receive_v2(
+        &mut reveiver_session,
+        &receiver_session_id,
+        None,
+        receiver,
+        &relays,
+    )
+    .await
+    {
+        Ok(()) => sessions::remove(&receiver_session_id)?,
+        Err(e) => println!("[PayjoinV2] ERROR(receiver): {}", e),
+    }
 
-    println!("[PayjoinV2] Finalizing PSBT...");
-    if let Some(tx) = sender.finalize_psbt(&psbt, Some(true))?.hex {
-        println!("[PayjoinV2] Sending Tx...");
-        let txid = sender.send_raw_transaction(&tx)?;
+    // **********************
+    // Inside the Sender:
+    // Sender polls for the proposal, checks, signs and broadcasts it (or the original tx)
+    let outcome = finish_send(sender, &sender_session_id, &get_ctx, &psbt, &relays).await?;
+    fallback::check(receiver)?;
+    forwarding::settle(receiver)?;
 
+    wait_for_block(miner, 1)?;
+    println!(
+        "[PayjoinV2] Snd(after): {:?}",
+        get_client_balance(sender)?.to_btc()
+    );
+    println!(
+        "[PayjoinV2] Rcv(after): {:?}",
+        get_client_balance(receiver)?.to_btc()
+    );
+    verify_payjoin(
+        sender,
+        receiver,
+        balances_before,
+        &outcome,
+        amount,
+        psbt.fee()? + sender_params.max_additional_fee,
+        forwards,
+        sender_params.disable_output_substitution,
+    )?;
+
+    Ok(outcome.tx().compute_txid())
+}
+
+pub async fn post_original(
+    pj_sender: &Sender,
+    relays: &RelayPool,
+) -> Result<V2GetContext, Box<dyn std::error::Error>> {
+    println!("[PayjoinV2] Sender posting original PSBT");
+    relays
+        .exchange(
+            "post the original PSBT",
+            &mut (),
+            |_, relay| Ok(pj_sender.extract_v2(relay.clone())?),
+            |_, res, send_ctx| Ok(send_ctx.process_response(res)?),
+        )
+        .await
+}
+
+/// Polls for the proposal and broadcasts the payjoin (or the original tx), the session is done then.
+pub async fn finish_send(
+    sender: &Client,
+    session_id: &str,
+    get_ctx: &V2GetContext,
+    original_psbt: &Psbt,
+    relays: &RelayPool,
+) -> Result<SendOutcome, Box<dyn std::error::Error>> {
+    let proposal = poll_proposal(get_ctx, relays).await;
+    let outcome = broadcast_or_fallback(sender, original_psbt, proposal)?;
+    if matches!(outcome, SendOutcome::Fallback(_)) {
+        println!("[PayjoinV2] Receiver did not payjoin, the original tx was broadcast");
+    }
+    sessions::remove(session_id)?;
+    Ok(outcome)
+}
+
+pub fn list_v2_sessions() -> Result<(), Box<dyn std::error::Error>> {
+    let sessions = sessions::list()?;
+    if sessions.is_empty() {
+        println!("[PayjoinV2] No pending sessions");
+    }
+    for session in sessions {
+        let expires = if session.is_expired() {
+            "expired".to_string()
+        } else {
+            format!("expires in {}s", session.expiry - now())
+        };
         println!(
-            "[PayjoinV2] Snd(after): {:?}",
-            get_client_balance(sender)?.to_btc()
-        );
-        println!(
-            "[PayjoinV2] Rcv(after): {:?}",
-            get_client_balance(receiver)?.to_btc()
+            "[PayjoinV2] {} | {} | wallet={} | {} | directory={}{}",
+            session.id,
+            session.role(),
+            session.wallet,
+            expires,
+            session.directory,
+            if session.local { " (local)" } else { "" }
         );
+    }
+    Ok(())
+}
 
-        Ok(txid)
-    } else {
-        Err("fail".into())
+/// Picks up a persisted session where it was left: the receiver goes back to polling for the
+/// original PSBT, the sender re-posts it and polls for the proposal (or falls back if expired).
+pub async fn resume_v2_session(id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let session = sessions::get(id)?;
+    println!(
+        "[PayjoinV2] Resuming {} session {}",
+        session.role(),
+        session.id
+    );
+    let endpoints = session_endpoints(&session).await?;
+    let client = bitcoind_client(&session.wallet)?;
+    let expired = session.is_expired();
+
+    match session.state {
+        SessionState::Receiver(mut receiver_session) => {
+            if expired {
+                sessions::remove(id)?;
+                return Err(format!("Receiver session {} expired", id).into());
+            }
+            receive_v2(
+                &mut receiver_session,
+                id,
+                session.checked_original,
+                &client,
+                &endpoints.relays,
+            )
+            .await?;
+            sessions::remove(id)?;
+        }
+        SessionState::Sender {
+            sender: pj_sender,
+            original_psbt,
+        } => {
+            let original_psbt = Psbt::from_str(&original_psbt)?;
+            let outcome = if expired {
+                let outcome = broadcast_or_fallback(
+                    &client,
+                    &original_psbt,
+                    Err("the session expired".into()),
+                )?;
+                sessions::remove(id)?;
+                outcome
+            } else {
+                let get_ctx = post_original(&pj_sender, &endpoints.relays).await?;
+                finish_send(&client, id, &get_ctx, &original_psbt, &endpoints.relays).await?
+            };
+            println!("[PayjoinV2] Broadcast {}", outcome.tx().compute_txid());
+        }
+    }
+    Ok(())
+}
+
+/// Drops a pending session. The sender can still broadcast its original tx so the payment goes out.
+pub fn cancel_v2_session(
+    id: &str,
+    broadcast_original: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session = sessions::get(id)?;
+    if let SessionState::Sender { original_psbt, .. } = &session.state {
+        if broadcast_original {
+            let client = bitcoind_client(&session.wallet)?;
+            broadcast_or_fallback(
+                &client,
+                &Psbt::from_str(original_psbt)?,
+                Err("the session was cancelled".into()),
+            )?;
+        } else {
+            println!("[PayjoinV2] Original PSBT of {} was not broadcast", id);
+        }
+    }
+    sessions::remove(id)?;
+    println!("[PayjoinV2] Cancelled {} session {}", session.role(), id);
+    Ok(())
+}
+
+/// Receiver side of a v2 session: polls its subdirectory until the sender's original PSBT shows
+/// up, checks it, records that in the persisted session `persisted_id`, and posts the payjoin
+/// proposal back.
+async fn receive_v2(
+    session: &mut Receiver,
+    persisted_id: &str,
+    checked_before: Option<Txid>,
+    receiver: &Client,
+    relays: &RelayPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_id = session.id().to_string();
+    let proposal = loop {
+        if let Some(proposal) = poll_original(session, relays).await? {
+            break proposal;
+        }
+        println!("[PayjoinV2] Receiver waiting for the original PSBT...");
+        tokio::time::sleep(V2_POLL_INTERVAL).await;
+    };
+
+    let to_broadcast_in_failure_case = proposal.extract_tx_to_schedule_broadcast();
+    let payjoin = check_v2_proposal(proposal, receiver, checked_before)?;
+    sessions::mark_checked(persisted_id, to_broadcast_in_failure_case.compute_txid())?;
+    let payjoin_proposal = contribute_v2_proposal(
+        payjoin,
+        receiver,
+        &session_id,
+        &to_broadcast_in_failure_case,
+    )?;
+    post_proposal(payjoin_proposal, relays).await
+}
+
+/// One look at the receiver's subdirectory, None while the sender hasn't posted yet.
+pub async fn poll_original(
+    session: &mut Receiver,
+    relays: &RelayPool,
+) -> Result<Option<UncheckedProposal>, Box<dyn std::error::Error>> {
+    relays
+        .exchange(
+            "poll for the original PSBT",
+            session,
+            |session, _| Ok(session.extract_req()?),
+            |session, res, ctx| Ok(session.process_res(res, ctx)?),
+        )
+        .await
+}
+
+/// Posts the receiver's payjoin proposal to the sender's reply mailbox.
+pub async fn post_proposal(
+    mut payjoin_proposal: PayjoinProposal,
+    relays: &RelayPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "[PayjoinV2] Receiver's Payjoin proposal PSBT(inputs.len): {:#?}",
+        payjoin_proposal.psbt().inputs.len()
+    );
+    relays
+        .exchange(
+            "post the payjoin proposal",
+            &mut payjoin_proposal,
+            |proposal, _| Ok(proposal.extract_v2_req()?),
+            |proposal, res, ctx| Ok(proposal.process_res(res, ctx)?),
+        )
+        .await?;
+    println!("[PayjoinV2] Receiver posted its proposal");
+    Ok(())
+}
+
+/// Sender side: polls for the receiver's proposal, up to the configured sender timeout.
+async fn poll_proposal(
+    ctx: &V2GetContext,
+    relays: &RelayPool,
+) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let timeout = config().sender_timeout;
+    let started = Instant::now();
+    loop {
+        let proposal = relays
+            .exchange(
+                "poll for the payjoin proposal",
+                &mut (),
+                |_, relay| Ok(ctx.extract_req(relay.clone())?),
+                |_, res, ohttp_ctx| Ok(ctx.process_response(res, ohttp_ctx)?),
+            )
+            .await?;
+        if let Some(psbt) = proposal {
+            return Ok(psbt);
+        }
+        if started.elapsed() >= timeout {
+            return Err(format!("No payjoin proposal after {:?}", timeout).into());
+        }
+        println!("[PayjoinV2] Sender waiting for the payjoin proposal...");
+        tokio::time::sleep(V2_POLL_INTERVAL).await;
     }
 }
@@ -0,0 +1,102 @@
+use bitcoincore_rpc::{bitcoincore_rpc_json::ListUnspentResultEntry, Client, RpcApi};
+use payjoin::{
+    bitcoin::{Amount, FeeRate, Transaction},
+    receive::InputPair,
+};
+
+use crate::config::ReceiverPolicy;
+
+use super::{error::ReceiverError, payjoin_v1::input_pair_from_list_unspent};
+
+/// Inputs a receiver contributes to a payjoin, following its `ReceiverPolicy`.
+pub enum InputContribution<T = InputPair> {
+    /// Let rust-payjoin pick one of the candidates avoiding the UIH heuristics
+    PrivacyPick(Vec<T>),
+    /// Contribute all of these
+    Inputs(Vec<T>),
+}
+
+impl<T> InputContribution<T> {
+    fn map<U>(self, f: impl FnMut(T) -> U) -> InputContribution<U> {
+        match self {
+            InputContribution::PrivacyPick(candidates) => {
+                InputContribution::PrivacyPick(candidates.into_iter().map(f).collect())
+            }
+            InputContribution::Inputs(inputs) => {
+                InputContribution::Inputs(inputs.into_iter().map(f).collect())
+            }
+        }
+    }
+}
+
+/// Fee rate the original tx pays, from `testmempoolaccept` (it needs the inputs' values).
+fn original_fee_rate(
+    receiver: &Client,
+    tx: &Transaction,
+) -> Result<Option<FeeRate>, ReceiverError> {
+    let results = receiver
+        .test_mempool_accept(&[payjoin::bitcoin::consensus::encode::serialize_hex(tx)])
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()))?;
+    Ok(results.first().and_then(|res| {
+        let fee = res.fees.as_ref()?.base;
+        let vsize = res.vsize.filter(|vsize| *vsize > 0)?;
+        FeeRate::from_sat_per_vb(fee.to_sat() / vsize)
+    }))
+}
+
+/// Picks the receiver's contribution. The original tx fee rate is only looked up when the policy
+/// consolidates, to compare it with `consolidation_fee_rate`.
+pub fn select_inputs(
+    receiver: &Client,
+    policy: &ReceiverPolicy,
+    original_tx: &Transaction,
+) -> Result<InputContribution, ReceiverError> {
+    Ok(select_utxos(receiver, policy, original_tx)?.map(input_pair_from_list_unspent))
+}
+
+/// Same as `select_inputs`, as the wallet's UTXOs: for the PSBTs built without rust-payjoin.
+pub fn select_utxos(
+    receiver: &Client,
+    policy: &ReceiverPolicy,
+    original_tx: &Transaction,
+) -> Result<InputContribution<ListUnspentResultEntry>, ReceiverError> {
+    let mut candidates: Vec<ListUnspentResultEntry> = receiver
+        .list_unspent(None, None, None, None, None)
+        .map_err(|e| ReceiverError::Unavailable(e.to_string()))?;
+    if candidates.is_empty() {
+        return Err(ReceiverError::Unavailable(
+            "Receiver has no UTXOs to contribute".to_string(),
+        ));
+    }
+
+    let consolidate = match policy.consolidation_fee_rate {
+        Some(threshold) => original_fee_rate(receiver, original_tx)?
+            .map(|fee_rate| fee_rate <= threshold)
+            .unwrap_or(false),
+        None => false,
+    };
+    let max_inputs = if consolidate {
+        policy.consolidation_max_inputs.max(policy.max_inputs)
+    } else {
+        policy.max_inputs
+    };
+
+    if max_inputs <= 1 {
+        return Ok(InputContribution::PrivacyPick(candidates));
+    }
+
+    // Consolidating sweeps the smallest UTXOs, otherwise the largest ones keep the input count low
+    if consolidate {
+        candidates.sort_by_key(|utxo| utxo.amount);
+    } else {
+        candidates.sort_by_key(|utxo| std::cmp::Reverse(utxo.amount));
+    }
+    let inputs: Vec<_> = candidates.into_iter().take(max_inputs).collect();
+    println!(
+        "[ReceiverPolicy] Contributing {} input(s) ({}) [consolidate={}]",
+        inputs.len(),
+        inputs.iter().map(|utxo| utxo.amount).sum::<Amount>(),
+        consolidate
+    );
+    Ok(InputContribution::Inputs(inputs))
+}
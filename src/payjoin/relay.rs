@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+
+use payjoin::Url;
+use tokio::{
+    io::{copy_bidirectional, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::payjoin_v1::BoxError;
+
+/// Localhost stand-in for an OHTTP relay: POSTs are forwarded to the directory's gateway and
+/// CONNECT tunnels let clients fetch the directory's OHTTP keys through it (as a proxy).
+pub struct LocalRelay {
+    url: Url,
+    handle: JoinHandle<()>,
+}
+
+impl LocalRelay {
+    pub async fn start(
+        bind: SocketAddr,
+        gateway: Url,
+        gateway_cert_pem: &str,
+    ) -> Result<LocalRelay, BoxError> {
+        let listener = TcpListener::bind(bind).await?;
+        let addr = listener.local_addr()?;
+        let url = Url::parse(&format!("http://{}:{}/", addr.ip(), addr.port()))?;
+        let https = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(gateway_cert_pem.as_bytes())?)
+            .build()?;
+
+        let handle = tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("[Relay] ERROR(accept): {}", e);
+                        continue;
+                    }
+                };
+                let (https, gateway) = (https.clone(), gateway.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &https, &gateway).await {
+                        println!("[Relay] ERROR: {}", e);
+                    }
+                });
+            }
+        });
+
+        println!("[Relay] Listening at {} (gateway={})", url, gateway);
+        Ok(LocalRelay { url, handle })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl Drop for LocalRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    https: &reqwest::Client,
+    gateway: &Url,
+) -> Result<(), BoxError> {
+    let mut stream = BufReader::new(stream);
+
+    // Request line and headers, we only care about the target and the body length
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    match method {
+        "CONNECT" => {
+            let mut upstream = TcpStream::connect(target).await?;
+            stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            copy_bidirectional(stream.get_mut(), &mut upstream).await?;
+        }
+        "POST" => {
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).await?;
+            let res = https
+                .post(gateway.clone())
+                .header("Content-Type", "message/ohttp-req")
+                .body(body)
+                .send()
+                .await?;
+            let status = res.status();
+            let body = res.bytes().await?;
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Type: message/ohttp-res\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            let stream = stream.get_mut();
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(&body).await?;
+        }
+        _ => {
+            stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+        }
+    }
+    Ok(())
+}
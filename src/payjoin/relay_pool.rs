@@ -0,0 +1,123 @@
+use payjoin::{OhttpKeys, Request, Url};
+use rand::seq::SliceRandom;
+
+use super::{payjoin_v1::BoxError, tls};
+
+/// OHTTP relays a v2 client spreads its requests over. Every request goes through a relay picked
+/// at random, the others are tried in turn when it is unreachable or its response is malformed.
+#[derive(Clone)]
+pub struct RelayPool {
+    relays: Vec<Url>,
+    /// Certificate of the local stand-ins, None when using the configured (public) ones
+    cert_pem: Option<String>,
+}
+
+impl RelayPool {
+    pub fn new(relays: Vec<Url>, cert_pem: Option<String>) -> Result<RelayPool, BoxError> {
+        if relays.is_empty() {
+            return Err("No OHTTP relay configured".into());
+        }
+        Ok(RelayPool { relays, cert_pem })
+    }
+
+    pub fn relays(&self) -> &[Url] {
+        &self.relays
+    }
+
+    /// All relays, in a random order.
+    fn shuffled(&self) -> Vec<Url> {
+        let mut relays = self.relays.clone();
+        relays.shuffle(&mut rand::thread_rng());
+        relays
+    }
+
+    /// A random relay, for the ones rust-payjoin keeps in its sessions (requests are still posted
+    /// through the pool, the OHTTP body does not depend on the relay it goes through).
+    pub fn pick(&self) -> Url {
+        self.shuffled().remove(0)
+    }
+
+    /// Fetches the directory's OHTTP keys through a relay (used as an HTTP CONNECT proxy), so the
+    /// directory never sees our IP.
+    pub async fn fetch_ohttp_keys(&self, directory: &Url) -> Result<OhttpKeys, BoxError> {
+        let mut last_error = None;
+        for relay in self.shuffled() {
+            match fetch_ohttp_keys(&relay, directory, self.cert_pem.as_deref()).await {
+                Ok(keys) => return Ok(keys),
+                Err(e) => {
+                    println!("[RelayPool] {} failed to fetch OHTTP keys: {}", relay, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(all_failed("fetch OHTTP keys", last_error))
+    }
+
+    /// One OHTTP round trip. `extract` builds the encapsulated request for the given relay and
+    /// `process` decapsulates the response; both get `state` so they can share the same session.
+    /// A relay that can't be reached, or whose response doesn't process, is skipped for the next.
+    pub async fn exchange<S, C, T>(
+        &self,
+        what: &str,
+        state: &mut S,
+        mut extract: impl FnMut(&mut S, &Url) -> Result<(Request, C), BoxError>,
+        mut process: impl FnMut(&mut S, &[u8], C) -> Result<T, BoxError>,
+    ) -> Result<T, BoxError> {
+        let mut last_error = None;
+        for relay in self.shuffled() {
+            let (req, ctx) = extract(state, &relay)?;
+            let result = match post_ohttp(&relay, req, self.cert_pem.as_deref()).await {
+                Ok(res) => process(state, &res, ctx),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    println!("[RelayPool] {} failed to {}: {}", relay, what, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(all_failed(what, last_error))
+    }
+}
+
+fn all_failed(what: &str, last_error: Option<BoxError>) -> BoxError {
+    match last_error {
+        Some(e) => format!("Every OHTTP relay failed to {}, last error: {}", what, e).into(),
+        None => format!("No OHTTP relay to {}", what).into(),
+    }
+}
+
+async fn fetch_ohttp_keys(
+    relay: &Url,
+    directory: &Url,
+    cert_pem: Option<&str>,
+) -> Result<OhttpKeys, BoxError> {
+    let res = tls::client_builder(cert_pem)?
+        .proxy(reqwest::Proxy::all(relay.as_str())?)
+        .build()?
+        .get(directory.join("/ohttp-keys")?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(OhttpKeys::decode(&res.bytes().await?)?)
+}
+
+/// Posts an OHTTP encapsulated request to the relay, returns the encapsulated response.
+async fn post_ohttp(
+    relay: &Url,
+    req: Request,
+    cert_pem: Option<&str>,
+) -> Result<Vec<u8>, BoxError> {
+    tls::ensure_authenticated(relay)?;
+    let res = tls::client_builder(cert_pem)?
+        .build()?
+        .post(relay.clone())
+        .body(req.body)
+        .header("Content-Type", payjoin::V2_REQ_CONTENT_TYPE)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(res.bytes().await?.to_vec())
+}
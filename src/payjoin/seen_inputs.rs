@@ -0,0 +1,104 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use bitcoincore_rpc::{Client, RpcApi};
+use payjoin::bitcoin::{OutPoint, Transaction};
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+
+use super::{
+    payjoin_v1::BoxError,
+    store::{load_json, now, save_json},
+};
+
+const SEEN_INPUTS_FILE: &str = "seen_inputs.json";
+
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// An outpoint the receiver saw in an original PSBT.
+#[derive(Debug, Serialize, Deserialize)]
+struct SeenInput {
+    outpoint: OutPoint,
+    /// Unix timestamp of the first time we saw it
+    seen_at: u64,
+    /// Payjoin session it was seen in (the original txid for v1, the session id for v2)
+    session_id: String,
+}
+
+fn seen_inputs_path() -> PathBuf {
+    config().data_dir.join(SEEN_INPUTS_FILE)
+}
+
+/// True if `outpoint` is recorded from an earlier proposal (the original PSBT is a replay or
+/// someone probing our UTXOs).
+pub fn check(outpoint: &OutPoint) -> Result<bool, BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let seen: Vec<SeenInput> = load_json(&seen_inputs_path())?;
+    match seen.iter().find(|input| input.outpoint == *outpoint) {
+        Some(input) => {
+            println!(
+                "[SeenInputs] {} already seen at {} in session {}",
+                outpoint, input.seen_at, input.session_id
+            );
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Records the inputs of the original tx as seen in `session_id`, once our proposal for it is
+/// final: an original rejected on the way doesn't burn the sender's outpoints for a retry.
+pub fn record(original_tx: &Transaction, session_id: &str) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = seen_inputs_path();
+    let mut seen: Vec<SeenInput> = load_json(&path)?;
+    let before = seen.len();
+    for txin in &original_tx.input {
+        if seen
+            .iter()
+            .any(|input| input.outpoint == txin.previous_output)
+        {
+            continue;
+        }
+        seen.push(SeenInput {
+            outpoint: txin.previous_output,
+            seen_at: now(),
+            session_id: session_id.to_string(),
+        });
+    }
+    if seen.len() != before {
+        save_json(&path, &seen)?;
+    }
+    Ok(())
+}
+
+/// Drops the outpoints that are spent on chain, they can't be replayed anymore.
+/// NOTE: `gettxout` can't tell a spent outpoint from an unknown one, so an outpoint whose funding
+/// tx is still in the mempool and already spent by another mempool tx is pruned as well.
+pub fn prune(bitcoind: &Client) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = seen_inputs_path();
+    let seen: Vec<SeenInput> = load_json(&path)?;
+    if seen.is_empty() {
+        return Ok(());
+    }
+    let before = seen.len();
+    let mut unspent = Vec::with_capacity(before);
+    for input in seen {
+        let OutPoint { txid, vout } = input.outpoint;
+        let in_utxo_set = bitcoind.get_tx_out(&txid, vout, Some(false))?.is_some();
+        let in_mempool = bitcoind.get_tx_out(&txid, vout, Some(true))?.is_some();
+        if in_utxo_set || in_mempool {
+            unspent.push(input);
+        }
+    }
+    if unspent.len() != before {
+        println!(
+            "[SeenInputs] Pruned {} spent outpoint(s), {} left",
+            before - unspent.len(),
+            unspent.len()
+        );
+        save_json(&path, &unspent)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use std::str::FromStr;
+
+use bitcoincore_rpc::{Client, RpcApi};
+use payjoin::{
+    bitcoin::{Amount, FeeRate, Psbt, Transaction},
+    send::{Sender, SenderBuilder, V1Context},
+    PjUri, Request,
+};
+
+use crate::{
+    config::config,
+    weight::{FeeCheck, Signatures},
+};
+
+use super::{payjoin_v1::BoxError, tls};
+
+/// BIP78 optional parameters the sender appends to the `pj=` endpoint.
+#[derive(Debug, Clone)]
+pub struct SenderParams {
+    /// `maxadditionalfeecontribution`: max fee the receiver may take from our change
+    pub max_additional_fee: Amount,
+    /// `additionalfeeoutputindex`: output the fee contribution comes from, the change if None
+    pub additional_fee_output_index: Option<usize>,
+    /// `minfeerate`: min fee rate the payjoin must pay
+    pub min_fee_rate: FeeRate,
+    /// `disableoutputsubstitution`: the receiver must keep its output as in the original PSBT
+    pub disable_output_substitution: bool,
+    /// Lower the fee contribution to the change value instead of failing when it doesn't fit
+    pub clamp_fee_contribution: bool,
+}
+
+impl SenderParams {
+    /// Checks the parameters make sense for `psbt`, so we fail with a clear message instead of
+    /// sending a request no receiver can satisfy.
+    fn validate(&self, psbt: &Psbt, uri: &PjUri) -> Result<(), BoxError> {
+        let outputs = &psbt.unsigned_tx.output;
+        let payee = uri.address.script_pubkey();
+        let change: Vec<usize> = (0..outputs.len())
+            .filter(|i| outputs[*i].script_pubkey != payee)
+            .collect();
+
+        let fee_output = match self.additional_fee_output_index {
+            Some(index) if index >= outputs.len() => {
+                return Err(format!(
+                    "additionalfeeoutputindex {} is out of range, the original PSBT has {} outputs",
+                    index,
+                    outputs.len()
+                )
+                .into())
+            }
+            Some(index) if outputs[index].script_pubkey == payee => {
+                return Err(format!(
+                    "additionalfeeoutputindex {} is the receiver's output, the fee contribution must come from our change",
+                    index
+                )
+                .into())
+            }
+            Some(index) => Some(index),
+            None if self.max_additional_fee == Amount::ZERO => None,
+            None => match change.as_slice() {
+                [] => return Err(format!(
+                    "maxadditionalfeecontribution is {} but the original PSBT has no change output to take it from, set it to 0",
+                    self.max_additional_fee
+                )
+                .into()),
+                [index] => Some(*index),
+                _ => return Err(format!(
+                    "the original PSBT has {} candidate change outputs ({:?}), set additionalfeeoutputindex",
+                    change.len(),
+                    change
+                )
+                .into()),
+            },
+        };
+
+        if let Some(index) = fee_output {
+            let value = outputs[index].value;
+            if !self.clamp_fee_contribution && value < self.max_additional_fee {
+                return Err(format!(
+                    "change output {} holds {}, less than maxadditionalfeecontribution {}, lower it or clamp the fee contribution",
+                    index, value, self.max_additional_fee
+                )
+                .into());
+            }
+        }
+
+        if self.max_additional_fee == Amount::ZERO {
+            let check = FeeCheck::new(psbt, Signatures::LowR)?;
+            let fee_rate = check.fee / check.weight;
+            if fee_rate < self.min_fee_rate {
+                return Err(format!(
+                    "minfeerate {} is above the original PSBT fee rate ({}) and maxadditionalfeecontribution is 0, no receiver could satisfy it",
+                    self.min_fee_rate, fee_rate
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validates `params` against the original PSBT and builds the BIP78 sender with them.
+pub fn build_sender(psbt: Psbt, uri: PjUri, params: &SenderParams) -> Result<Sender, BoxError> {
+    params.validate(&psbt, &uri)?;
+    println!(
+        "[Sender] maxadditionalfeecontribution={} | additionalfeeoutputindex={:?} | minfeerate={} | disableoutputsubstitution={}",
+        params.max_additional_fee,
+        params.additional_fee_output_index,
+        params.min_fee_rate,
+        params.disable_output_substitution
+    );
+    let sender = SenderBuilder::from_psbt_and_uri(psbt, uri)
+        .map_err(|e| format!("Invalid original PSBT: {}", e))?
+        .always_disable_output_substitution(params.disable_output_substitution)
+        .build_with_additional_fee(
+            params.max_additional_fee,
+            params.additional_fee_output_index,
+            params.min_fee_rate,
+            params.clamp_fee_contribution,
+        )
+        .map_err(|e| format!("Invalid sender parameters: {}", e))?;
+    Ok(sender)
+}
+
+/// How a payment ended up being broadcast.
+pub enum SendOutcome {
+    /// The receiver's proposal passed the checks, the payjoin transaction was broadcast.
+    Payjoin(Transaction),
+    /// Something went wrong with the payjoin, the original transaction was broadcast instead.
+    Fallback(Transaction),
+}
+
+impl SendOutcome {
+    pub fn tx(&self) -> &Transaction {
+        match self {
+            SendOutcome::Payjoin(tx) | SendOutcome::Fallback(tx) => tx,
+        }
+    }
+}
+
+/// Posts the original PSBT to the receiver's `pj=` endpoint and broadcasts the resulting payjoin.
+/// If the receiver errors, does not answer within the configured timeout or replies with an
+/// invalid proposal, the original PSBT is signed and broadcast so the payment never gets stuck.
+pub async fn send_v1(
+    sender: &Client,
+    original_psbt: &Psbt,
+    req: Request,
+    ctx: V1Context,
+    root_cert_pem: Option<&str>,
+) -> Result<SendOutcome, BoxError> {
+    let proposal = request_payjoin(req, ctx, root_cert_pem).await;
+    broadcast_or_fallback(sender, original_psbt, proposal)
+}
+
+/// Broadcasts the checked payjoin `proposal`, or the original PSBT if there is none (the receiver
+/// failed, timed out or replied with an invalid proposal) or it can't be broadcast.
+pub fn broadcast_or_fallback(
+    sender: &Client,
+    original_psbt: &Psbt,
+    proposal: Result<Psbt, BoxError>,
+) -> Result<SendOutcome, BoxError> {
+    let payjoin = match proposal {
+        Ok(proposal) => sign_and_finalize(sender, &proposal)
+            .and_then(|tx| broadcast(sender, tx))
+            .map_err(|e| format!("Failed to broadcast the payjoin: {}", e)),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match payjoin {
+        Ok(tx) => {
+            println!("[Sender] Payjoin broadcast: {}", tx.compute_txid());
+            Ok(SendOutcome::Payjoin(tx))
+        }
+        Err(e) => {
+            println!(
+                "[Sender] Payjoin failed ({}), broadcasting the original tx",
+                e
+            );
+            let tx = broadcast(sender, sign_and_finalize(sender, original_psbt)?)?;
+            println!("[Sender] Original tx broadcast: {}", tx.compute_txid());
+            Ok(SendOutcome::Fallback(tx))
+        }
+    }
+}
+
+async fn request_payjoin(
+    req: Request,
+    ctx: V1Context,
+    root_cert_pem: Option<&str>,
+) -> Result<Psbt, BoxError> {
+    let timeout = config().sender_timeout;
+    println!(
+        "[PayjoinV1][Sender] Posting original PSBT to {} (timeout={:?})",
+        req.url, timeout
+    );
+    tls::ensure_authenticated(&req.url)?;
+    let res = tls::client_builder(root_cert_pem)?
+        .timeout(timeout)
+        .build()?
+        .post(req.url)
+        .body(req.body)
+        .header("Content-Type", req.content_type)
+        .send()
+        .await?;
+
+    let status = res.status();
+    let body = res.bytes().await?.to_vec();
+    if !status.is_success() {
+        return Err(format!(
+            "Receiver replied {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )
+        .into());
+    }
+    Ok(ctx.process_response(&mut body.as_slice())?)
+}
+
+/// Signs the sender's inputs (no-op if they already are) and extracts the final transaction.
+pub fn sign_and_finalize(sender: &Client, psbt: &Psbt) -> Result<Transaction, BoxError> {
+    // Not signed yet, bitcoind fills in what the weight prediction needs (redeem/witness scripts)
+    let updated = sender
+        .wallet_process_psbt(&psbt.to_string(), Some(false), None, None)?
+        .psbt;
+    let check = FeeCheck::new(&Psbt::from_str(&updated)?, Signatures::LowR)?;
+    println!("[Sender] Fee check: {}", check);
+    check.ensure(FeeRate::BROADCAST_MIN)?;
+
+    let psbt = sender
+        .wallet_process_psbt(&psbt.to_string(), None, None, None)?
+        .psbt;
+    let psbt = sender
+        .finalize_psbt(&psbt, Some(false))?
+        .psbt
+        .ok_or("finalizepsbt should return a PSBT")?;
+    let psbt = Psbt::from_str(&psbt)?;
+    println!("[Sender] Final PSBT(inputs.len): {:#?}", &psbt.inputs.len());
+    println!(
+        "[Sender] Final PSBT(outputs.len): {:#?}",
+        &psbt.outputs.len()
+    );
+    Ok(psbt.extract_tx()?)
+}
+
+fn broadcast(sender: &Client, tx: Transaction) -> Result<Transaction, BoxError> {
+    sender.send_raw_transaction(&tx)?;
+    Ok(tx)
+}
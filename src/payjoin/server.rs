@@ -0,0 +1,216 @@
+use std::{
+    fs,
+    io::Read,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use payjoin::{receive::Headers, Url};
+use tiny_http::{Header, Method, Request as HttpRequest, Response, Server, SslConfig};
+
+use crate::client::bitcoind_client;
+
+use super::{
+    error::ReceiverError,
+    payjoin_v1::{handle_v1_pj_request, BoxError},
+};
+
+/// Path of the BIP78 endpoint, the `pj=` of the URIs points to it.
+pub const PJ_PATH: &str = "/payjoin";
+
+/// PEM encoded certificate and private key the server uses for TLS.
+#[derive(Clone)]
+pub struct TlsIdentity {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl TlsIdentity {
+    /// Self-signed certificate for `localhost`/`127.0.0.1`, only meant for local testing.
+    pub fn self_signed() -> Result<TlsIdentity, BoxError> {
+        let certified = rcgen::generate_simple_self_signed(vec![
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+        ])?;
+        Ok(TlsIdentity {
+            cert_pem: certified.cert.pem(),
+            key_pem: certified.key_pair.serialize_pem(),
+        })
+    }
+
+    pub fn from_files(cert: &Path, key: &Path) -> Result<TlsIdentity, BoxError> {
+        Ok(TlsIdentity {
+            cert_pem: fs::read_to_string(cert)?,
+            key_pem: fs::read_to_string(key)?,
+        })
+    }
+}
+
+/// Headers of the incoming HTTP request, as rust-payjoin wants them.
+struct RequestHeaders {
+    content_length: Option<String>,
+    content_type: Option<String>,
+}
+
+impl RequestHeaders {
+    fn new(headers: &[Header]) -> RequestHeaders {
+        let find = |name: &'static str| {
+            headers
+                .iter()
+                .find(|header| header.field.equiv(name))
+                .map(|header| header.value.as_str().to_string())
+        };
+        RequestHeaders {
+            content_length: find("Content-Length"),
+            content_type: find("Content-Type"),
+        }
+    }
+}
+
+impl Headers for RequestHeaders {
+    fn get_header(&self, key: &str) -> Option<&str> {
+        match key {
+            "content-length" => self.content_length.as_deref(),
+            "content-type" => self.content_type.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// BIP78 receiver endpoint, every request is handled with the `receiver_wallet` bitcoind wallet.
+pub struct V1Server {
+    server: Arc<Server>,
+    url: Url,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl V1Server {
+    pub fn start(
+        bind: SocketAddr,
+        receiver_wallet: &str,
+        tls: Option<TlsIdentity>,
+    ) -> Result<V1Server, BoxError> {
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let server = match tls {
+            Some(tls) => Server::https(
+                bind,
+                SslConfig {
+                    certificate: tls.cert_pem.into_bytes(),
+                    private_key: tls.key_pem.into_bytes(),
+                },
+            ),
+            None => Server::http(bind),
+        }
+        .map_err(|e| format!("Failed to bind payjoin server on {}: {}", bind, e))?;
+
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or("Payjoin server is not listening on an IP address")?;
+        // The self-signed certificate is only valid for localhost
+        let host = if addr.ip().is_loopback() {
+            "localhost".to_string()
+        } else {
+            addr.ip().to_string()
+        };
+        let url = Url::parse(&format!("{}://{}:{}{}", scheme, host, addr.port(), PJ_PATH))?;
+
+        let server = Arc::new(server);
+        let receiver = bitcoind_client(receiver_wallet)?;
+        let handle = {
+            let server = server.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle_request(request, &receiver);
+                }
+            })
+        };
+
+        println!("[PayjoinV1][Server] Listening at {}", url);
+        Ok(V1Server {
+            server,
+            url,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Blocks serving requests until the process is stopped.
+    pub fn wait(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for V1Server {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn handle_request(mut request: HttpRequest, receiver: &bitcoincore_rpc::Client) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((url.as_str(), ""));
+    println!("[PayjoinV1][Server] {} {}", request.method(), path);
+
+    let response = if *request.method() != Method::Post || path != PJ_PATH {
+        Response::from_string("Not Found").with_status_code(404)
+    } else {
+        match process_request(&mut request, query, receiver) {
+            Ok(psbt) => Response::from_string(psbt).with_header(header("text/plain")),
+            Err(e) => {
+                println!("[PayjoinV1][Server] Rejecting request: {}", e);
+                let status = match e {
+                    ReceiverError::Unavailable(_) => 503,
+                    _ => 400,
+                };
+                Response::from_string(e.to_json())
+                    .with_status_code(status)
+                    .with_header(header("application/json"))
+            }
+        }
+    };
+
+    if let Err(e) = request.respond(response) {
+        println!("[PayjoinV1][Server] ERROR(respond): {}", e);
+    }
+}
+
+fn process_request(
+    request: &mut HttpRequest,
+    query: &str,
+    receiver: &bitcoincore_rpc::Client,
+) -> Result<String, ReceiverError> {
+    check_version(query)?;
+    let headers = RequestHeaders::new(request.headers());
+    let mut body = vec![];
+    request
+        .as_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| ReceiverError::Unavailable(format!("Failed to read request: {}", e)))?;
+    handle_v1_pj_request(&body, query, headers, receiver, None, None, None)
+}
+
+fn check_version(query: &str) -> Result<(), ReceiverError> {
+    match url::form_urlencoded::parse(query.as_bytes()).find(|(key, _)| key == "v") {
+        Some((_, version)) if version != "1" => Err(ReceiverError::VersionUnsupported(format!(
+            "Version {} is not supported",
+            version
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn header(content_type: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .expect("content type header should be valid")
+}
@@ -0,0 +1,124 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use payjoin::{bitcoin::Txid, receive::v2::Receiver, send::Sender, Url};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::config::config;
+
+use super::{
+    payjoin_v1::BoxError,
+    store::{load_json, now, save_private_json},
+};
+
+const SESSIONS_FILE: &str = "v2_sessions.json";
+
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A pending v2 (BIP77) session, persisted so a restarted process can resume it.
+///
+/// NOTE: the file is plaintext JSON (readable by its owner only): the receiver's session keys
+/// and the sender's reply key are in it, anyone who can read it can decrypt the session's
+/// messages or answer in the receiver's name until the session expires.
+#[derive(Serialize, Deserialize)]
+pub struct V2Session {
+    pub id: String,
+    /// bitcoind wallet of the sender or receiver
+    pub wallet: String,
+    pub created_at: u64,
+    /// Unix timestamp the session expires at
+    pub expiry: u64,
+    pub directory: Url,
+    /// Sessions saved before relay pools had a single `ohttp_relay`
+    #[serde(alias = "ohttp_relay", deserialize_with = "one_or_many")]
+    pub ohttp_relays: Vec<Url>,
+    /// Directory and relays are the local stand-ins, resuming restarts them on the same ports
+    pub local: bool,
+    /// Original tx the receiver already checked (and recorded the inputs of), so resuming
+    /// doesn't take it for a replay
+    #[serde(default)]
+    pub checked_original: Option<Txid>,
+    pub state: SessionState,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum SessionState {
+    /// Holds the OHTTP keys, the directory subdirectory and the expiry
+    Receiver(Receiver),
+    /// The sender (pj endpoint, OHTTP keys and reply key) and the signed original PSBT (base64)
+    Sender {
+        sender: Sender,
+        original_psbt: String,
+    },
+}
+
+impl V2Session {
+    pub fn role(&self) -> &'static str {
+        match self.state {
+            SessionState::Receiver(_) => "receiver",
+            SessionState::Sender { .. } => "sender",
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        now() >= self.expiry
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Url>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Url),
+        Many(Vec<Url>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
+}
+
+fn sessions_path() -> PathBuf {
+    config().data_dir.join(SESSIONS_FILE)
+}
+
+pub fn list() -> Result<Vec<V2Session>, BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    load_json(&sessions_path())
+}
+
+pub fn get(id: &str) -> Result<V2Session, BoxError> {
+    list()?
+        .into_iter()
+        .find(|session| session.id == id)
+        .ok_or_else(|| format!("No pending v2 session {}", id).into())
+}
+
+/// Inserts the session, or replaces the one with the same id.
+pub fn save(session: V2Session) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = sessions_path();
+    let mut sessions: Vec<V2Session> = load_json(&path)?;
+    sessions.retain(|s| s.id != session.id);
+    println!("[Sessions] Saved {} session {}", session.role(), session.id);
+    sessions.push(session);
+    save_private_json(&path, &sessions)
+}
+
+/// Records that the receiver session `id` checked the original tx `txid`.
+pub fn mark_checked(id: &str, txid: Txid) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = sessions_path();
+    let mut sessions: Vec<V2Session> = load_json(&path)?;
+    if let Some(session) = sessions.iter_mut().find(|s| s.id == id) {
+        session.checked_original = Some(txid);
+    }
+    save_private_json(&path, &sessions)
+}
+
+pub fn remove(id: &str) -> Result<(), BoxError> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = sessions_path();
+    let mut sessions: Vec<V2Session> = load_json(&path)?;
+    sessions.retain(|s| s.id != id);
+    save_private_json(&path, &sessions)
+}
@@ -0,0 +1,59 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::payjoin_v1::BoxError;
+
+/// Reads a JSON file from the data dir, `T::default()` if it does not exist yet.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T, BoxError> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content).map_err(|e| format!("Failed to parse {:?}: {}", path, e))?)
+}
+
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<(), BoxError> {
+    write_json(path, value, false)
+}
+
+/// `save_json` for files holding secrets: only the owner can read them (unix).
+pub fn save_private_json<T: Serialize>(path: &Path, value: &T) -> Result<(), BoxError> {
+    write_json(path, value, true)
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T, private: bool) -> Result<(), BoxError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Write + rename so a crash never leaves a truncated file behind
+    let tmp = path.with_extension("json.tmp");
+    let _ = fs::remove_file(&tmp);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+        .open(&tmp)?
+        .write_all(serde_json::to_string_pretty(value)?.as_bytes())?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Unix timestamp (secs) the stores use.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
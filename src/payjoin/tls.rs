@@ -0,0 +1,59 @@
+use std::{fs, path::Path};
+
+use payjoin::Url;
+use url::Host;
+
+use crate::config::config;
+
+use super::payjoin_v1::BoxError;
+
+/// HTTPS client builder trusting what the `[tls]` config says: the system roots (plus the CA
+/// bundle if any), or only the trusted roots. These are roots, not pins: a server certificate
+/// they (or any intermediate under them) issued passes too. `extra_root_pem` is the self-signed
+/// certificate of the local stand-ins, trusted on top of that.
+pub fn client_builder(extra_root_pem: Option<&str>) -> Result<reqwest::ClientBuilder, BoxError> {
+    let trust = &config().tls;
+    let mut builder = reqwest::Client::builder();
+
+    if !trust.trusted_roots.is_empty() {
+        builder = builder.tls_built_in_root_certs(false);
+        for path in &trust.trusted_roots {
+            builder = builder.add_root_certificate(read_certificate(path)?);
+        }
+    } else if let Some(path) = &trust.ca_bundle {
+        let bundle = fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&bundle)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(pem) = extra_root_pem {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem.as_bytes())?);
+    }
+    Ok(builder)
+}
+
+fn read_certificate(path: &Path) -> Result<reqwest::Certificate, BoxError> {
+    let pem = fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    Ok(reqwest::Certificate::from_pem(&pem)?)
+}
+
+/// PSBTs only go over https (certificates are always verified), onion services, or plain http to
+/// this same host (the local stand-ins), anything else could be read or tampered with on the way.
+pub fn ensure_authenticated(url: &Url) -> Result<(), BoxError> {
+    let local_or_onion = match url.host() {
+        Some(Host::Domain(domain)) => domain == "localhost" || domain.ends_with(".onion"),
+        Some(Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    };
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if local_or_onion => Ok(()),
+        _ => Err(format!(
+            "Refusing to send a PSBT to {}, the connection can't be authenticated (use https)",
+            url
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,413 @@
+use std::{fmt, str::FromStr};
+
+use payjoin::{
+    bitcoin::{
+        bech32::{primitives::decode::CheckedHrpstring, NoChecksum},
+        secp256k1::PublicKey,
+        Address, Amount, Network,
+    },
+    PjUriBuilder, Uri, UriExt, Url,
+};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+
+use super::{payjoin_v1::BoxError, store::now, tls};
+
+// Payjoin params carried in the fragment of a v2 `pj=` endpoint (bech32, no checksum)
+const RECEIVER_KEY_HRP: &str = "RK";
+const OHTTP_KEYS_HRP: &str = "OH";
+const EXPIRY_HRP: &str = "EX";
+
+/// What goes into a BIP21 payjoin URI.
+#[derive(Debug, Clone)]
+pub struct UriParams {
+    pub address: Address,
+    pub amount: Option<Amount>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// `pj=`: the BIP78 endpoint, or the v2 directory subdirectory with its fragment params
+    pub pj: Url,
+    /// `pjos=0`: the sender must not let the receiver substitute its output
+    pub disable_output_substitution: bool,
+}
+
+/// A payjoin URI that passed `parse`.
+#[derive(Debug, Clone)]
+pub struct ParsedUri {
+    pub params: UriParams,
+    /// Set when the endpoint is a v2 (BIP77) one
+    pub v2: Option<V2Params>,
+}
+
+/// BIP77 params of the `pj=` fragment.
+#[derive(Debug, Clone)]
+pub struct V2Params {
+    /// Key the sender encrypts the original PSBT to
+    pub receiver_key: PublicKey,
+    /// Directory's OHTTP key config: key id and compressed public key
+    pub ohttp_keys: Vec<u8>,
+    /// Unix timestamp the receiver stops polling at
+    pub expiry: u64,
+}
+
+/// Builds the URI, the result is parsed back so we never hand out one we'd reject.
+pub fn generate(params: &UriParams, network: Network) -> Result<String, BoxError> {
+    let mut builder =
+        PjUriBuilder::new(params.address.clone(), params.pj.clone(), None, None, None)
+            .pjos(params.disable_output_substitution);
+    if let Some(amount) = params.amount {
+        builder = builder.amount(amount);
+    }
+    if let Some(label) = &params.label {
+        builder = builder.label(label.clone());
+    }
+    if let Some(message) = &params.message {
+        builder = builder.message(message.clone());
+    }
+    let uri = builder.build().to_string();
+    parse(&uri, network)?;
+    Ok(uri)
+}
+
+/// Parses a BIP21 payjoin URI and checks it is one a sender on `network` can pay.
+pub fn parse(uri: &str, network: Network) -> Result<ParsedUri, BoxError> {
+    let checked = Uri::from_str(uri)
+        .map_err(|e| format!("Not a BIP21 URI: {}", e))?
+        .require_network(network)
+        .map_err(|e| format!("Address is not for {}: {}", network, e))?;
+    let amount = checked.amount;
+    let address = checked.address.clone();
+    if checked.check_pj_supported().is_err() {
+        return Err("URI has no valid pj= parameter, it's not a payjoin one".into());
+    }
+
+    // rust-payjoin doesn't expose the raw params, read them from the query ourselves
+    let url = Url::parse(uri).map_err(|e| format!("Malformed URI: {}", e))?;
+    let mut pj = None;
+    let mut pjos = None;
+    let mut label = None;
+    let mut message = None;
+    // BIP21 follows RFC 3986, a `+` (the v2 fragment separator) is not a form-encoded space
+    let query = url.query().unwrap_or_default().replace('+', "%2B");
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        let slot = match key.as_ref() {
+            "pj" => &mut pj,
+            "pjos" => &mut pjos,
+            "label" => &mut label,
+            "message" => &mut message,
+            _ => continue,
+        };
+        if slot.replace(value.into_owned()).is_some() {
+            return Err(format!("Parameter {} appears more than once", key).into());
+        }
+    }
+
+    let pj = pj.ok_or("URI has no pj= parameter")?;
+    let pj = Url::parse(&pj).map_err(|e| format!("pj= is not a URL ({}): {}", e, pj))?;
+    check_endpoint(&pj)?;
+    let disable_output_substitution = match pjos.as_deref() {
+        None | Some("1") => false,
+        Some("0") => true,
+        Some(other) => return Err(format!("pjos must be 0 or 1, got {:?}", other).into()),
+    };
+    let v2 = match pj.fragment() {
+        Some(fragment) if !fragment.is_empty() => Some(parse_v2_fragment(fragment)?),
+        _ => None,
+    };
+
+    Ok(ParsedUri {
+        params: UriParams {
+            address,
+            amount,
+            label,
+            message,
+            pj,
+            disable_output_substitution,
+        },
+        v2,
+    })
+}
+
+/// The endpoint must be one the sender would post to: https, http on a Tor hidden service, or
+/// http to this host (the local stand-ins).
+fn check_endpoint(pj: &Url) -> Result<(), BoxError> {
+    pj.host_str().ok_or("pj= endpoint has no host")?;
+    tls::ensure_authenticated(pj).map_err(|e| format!("pj= endpoint rejected: {}", e).into())
+}
+
+fn parse_v2_fragment(fragment: &str) -> Result<V2Params, BoxError> {
+    let mut receiver_key = None;
+    let mut ohttp_keys = None;
+    let mut expiry = None;
+    for param in fragment.split('+') {
+        let decoded = CheckedHrpstring::new::<NoChecksum>(param)
+            .map_err(|e| format!("v2 fragment parameter {:?} is not bech32: {}", param, e))?;
+        let hrp = decoded.hrp().to_string().to_uppercase();
+        let bytes: Vec<u8> = decoded.byte_iter().collect();
+        let duplicate = match hrp.as_str() {
+            RECEIVER_KEY_HRP => {
+                let key = PublicKey::from_slice(&bytes)
+                    .map_err(|e| format!("v2 receiver key (RK) is invalid: {}", e))?;
+                receiver_key.replace(key).is_some()
+            }
+            OHTTP_KEYS_HRP => {
+                if bytes.len() != 34 {
+                    return Err(format!(
+                        "v2 OHTTP keys (OH) must be 34 bytes (key id + compressed key), got {}",
+                        bytes.len()
+                    )
+                    .into());
+                }
+                PublicKey::from_slice(&bytes[1..])
+                    .map_err(|e| format!("v2 OHTTP key (OH) is invalid: {}", e))?;
+                ohttp_keys.replace(bytes).is_some()
+            }
+            EXPIRY_HRP => {
+                let timestamp: [u8; 4] = bytes
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| format!("v2 expiry (EX) must be 4 bytes, got {}", bytes.len()))?;
+                expiry
+                    .replace(u32::from_be_bytes(timestamp) as u64)
+                    .is_some()
+            }
+            _ => return Err(format!("Unknown v2 fragment parameter {}", hrp).into()),
+        };
+        if duplicate {
+            return Err(format!("v2 fragment parameter {} appears more than once", hrp).into());
+        }
+    }
+
+    let expiry = expiry.ok_or("v2 fragment has no expiry (EX)")?;
+    if expiry <= now() {
+        return Err(format!("v2 URI expired {}s ago", now() - expiry).into());
+    }
+    Ok(V2Params {
+        receiver_key: receiver_key.ok_or("v2 fragment has no receiver key (RK)")?,
+        ohttp_keys: ohttp_keys.ok_or("v2 fragment has no OHTTP keys (OH)")?,
+        expiry,
+    })
+}
+
+impl fmt::Display for ParsedUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params = &self.params;
+        writeln!(f, "address : {}", params.address)?;
+        match params.amount {
+            Some(amount) => writeln!(f, "amount  : {}", amount)?,
+            None => writeln!(f, "amount  : (any)")?,
+        }
+        if let Some(label) = &params.label {
+            writeln!(f, "label   : {}", label)?;
+        }
+        if let Some(message) = &params.message {
+            writeln!(f, "message : {}", message)?;
+        }
+        writeln!(f, "pj      : {}", params.pj)?;
+        write!(
+            f,
+            "pjos    : {}",
+            if params.disable_output_substitution {
+                "0 (output substitution disabled)"
+            } else {
+                "1 (output substitution allowed)"
+            }
+        )?;
+        match &self.v2 {
+            Some(v2) => write!(
+                f,
+                "\nversion : 2\nrk      : {}\noh      : {}\nexpiry  : {} (in {}s)",
+                v2.receiver_key,
+                hex::encode(&v2.ohttp_keys),
+                v2.expiry,
+                v2.expiry.saturating_sub(now())
+            ),
+            None => write!(f, "\nversion : 1"),
+        }
+    }
+}
+
+/// QR code of `data` for the terminal: half-block characters, or `#` when `ascii` (for terminals
+/// or fonts without them). Dark modules on light ones with the quiet zone around, as the QR spec
+/// (and scanners) expect: the code reads from a terminal with dark text on a light background.
+pub fn render_qr(data: &str, ascii: bool) -> Result<String, BoxError> {
+    // BIP21 QR codes are smaller uppercase (alphanumeric mode), but the params are case sensitive
+    let code = QrCode::new(data.as_bytes())?;
+    let qr = if ascii {
+        code.render::<char>()
+            .module_dimensions(2, 1)
+            .dark_color('#')
+            .light_color(' ')
+            .quiet_zone(true)
+            .build()
+    } else {
+        code.render::<Dense1x2>()
+            .dark_color(Dense1x2::Dark)
+            .light_color(Dense1x2::Light)
+            .quiet_zone(true)
+            .build()
+    };
+    Ok(qr)
+}
+
+#[cfg(test)]
+mod tests {
+    use payjoin::bitcoin::{
+        bech32::{self, Hrp},
+        CompressedPublicKey,
+    };
+
+    use super::*;
+
+    // secp256k1 generator, a valid compressed key
+    const KEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn key() -> PublicKey {
+        PublicKey::from_str(KEY).unwrap()
+    }
+
+    fn address() -> Address {
+        Address::p2wpkh(&CompressedPublicKey(key()), Network::Regtest)
+    }
+
+    fn params(pj: &str) -> UriParams {
+        UriParams {
+            address: address(),
+            amount: Some(Amount::from_sat(50_000)),
+            label: Some("coffee shop".to_string()),
+            message: Some("order #42".to_string()),
+            pj: Url::parse(pj).unwrap(),
+            disable_output_substitution: true,
+        }
+    }
+
+    fn fragment_param(hrp: &str, bytes: &[u8]) -> String {
+        bech32::encode::<NoChecksum>(Hrp::parse(hrp).unwrap(), bytes)
+            .unwrap()
+            .to_uppercase()
+    }
+
+    fn ohttp_keys() -> Vec<u8> {
+        let mut keys = vec![1];
+        keys.extend(key().serialize());
+        keys
+    }
+
+    /// v2 endpoint with its `RK`, `OH` and `EX` fragment params, `None` leaves one out.
+    fn v2_pj(rk: Option<&[u8]>, oh: Option<&[u8]>, ex: Option<u64>) -> String {
+        let mut fragment = vec![];
+        if let Some(rk) = rk {
+            fragment.push(fragment_param(RECEIVER_KEY_HRP, rk));
+        }
+        if let Some(oh) = oh {
+            fragment.push(fragment_param(OHTTP_KEYS_HRP, oh));
+        }
+        if let Some(ex) = ex {
+            fragment.push(fragment_param(EXPIRY_HRP, &(ex as u32).to_be_bytes()));
+        }
+        format!("https://localhost:3000/SUBDIRECTORY#{}", fragment.join("+"))
+    }
+
+    fn valid_v2_pj() -> String {
+        v2_pj(
+            Some(&key().serialize()),
+            Some(&ohttp_keys()),
+            Some(now() + 600),
+        )
+    }
+
+    /// URI as a wallet would write it: `#` escaped in the pj= value, `+` left as is.
+    fn uri(pj: &str, extra: &str) -> String {
+        format!(
+            "bitcoin:{}?amount=0.0005&pj={}{}",
+            address(),
+            pj.replace('#', "%23"),
+            extra
+        )
+    }
+
+    fn assert_round_trip(params: &UriParams) -> ParsedUri {
+        let uri = generate(params, Network::Regtest).unwrap();
+        let parsed = parse(&uri, Network::Regtest).unwrap();
+        assert_eq!(parsed.params.address, params.address);
+        assert_eq!(parsed.params.amount, params.amount);
+        assert_eq!(parsed.params.label, params.label);
+        assert_eq!(parsed.params.message, params.message);
+        assert_eq!(parsed.params.pj.host_str(), params.pj.host_str());
+        assert_eq!(
+            parsed.params.disable_output_substitution,
+            params.disable_output_substitution
+        );
+        parsed
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let parsed = assert_round_trip(&params("https://localhost:3000/payjoin"));
+        assert!(parsed.v2.is_none());
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let pj = valid_v2_pj();
+        let parsed = assert_round_trip(&params(&pj));
+        let v2 = parsed.v2.unwrap();
+        assert_eq!(v2.receiver_key, key());
+        assert_eq!(v2.ohttp_keys, ohttp_keys());
+        assert!(v2.expiry > now());
+    }
+
+    #[test]
+    fn rejects_duplicate_params() {
+        let pj = "https://localhost:3000/payjoin";
+        assert!(parse(&uri(pj, ""), Network::Regtest).is_ok());
+        assert!(parse(&uri(pj, "&label=a&label=b"), Network::Regtest).is_err());
+        assert!(parse(&uri(pj, &format!("&pj={}", pj)), Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn rejects_unauthenticated_endpoints() {
+        let network = Network::Regtest;
+        assert!(parse(&uri("http://example.onion/payjoin", ""), network).is_ok());
+        assert!(parse(&uri("http://example.com/payjoin", ""), network).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_pjos() {
+        let pj = "https://localhost:3000/payjoin";
+        assert!(parse(&uri(pj, "&pjos=0"), Network::Regtest).is_ok());
+        assert!(parse(&uri(pj, "&pjos=2"), Network::Regtest).is_err());
+        assert!(parse(&uri(pj, "&pjos=false"), Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_or_expired_expiry() {
+        assert!(parse(&uri(&valid_v2_pj(), ""), Network::Regtest).is_ok());
+        let rk = key().serialize();
+        let missing = v2_pj(Some(&rk), Some(&ohttp_keys()), None);
+        assert!(parse(&uri(&missing, ""), Network::Regtest).is_err());
+        let expired = v2_pj(Some(&rk), Some(&ohttp_keys()), Some(now() - 60));
+        assert!(parse(&uri(&expired, ""), Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_receiver_or_ohttp_keys() {
+        let expiry = Some(now() + 600);
+        let rk = key().serialize();
+        let oh = ohttp_keys();
+        // x beyond the field size, on no curve
+        let mut not_a_key = [0xff; 33];
+        not_a_key[0] = 2;
+
+        let missing_rk = v2_pj(None, Some(&oh), expiry);
+        let bad_rk = v2_pj(Some(&not_a_key), Some(&oh), expiry);
+        let short_rk = v2_pj(Some(&rk[..32]), Some(&oh), expiry);
+        let missing_oh = v2_pj(Some(&rk), None, expiry);
+        let short_oh = v2_pj(Some(&rk), Some(&oh[..33]), expiry);
+        let mut bad_oh = vec![1];
+        bad_oh.extend(not_a_key);
+        let bad_oh = v2_pj(Some(&rk), Some(&bad_oh), expiry);
+        for pj in [missing_rk, bad_rk, short_rk, missing_oh, short_oh, bad_oh] {
+            assert!(parse(&uri(&pj, ""), Network::Regtest).is_err(), "{}", pj);
+        }
+    }
+}
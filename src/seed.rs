@@ -0,0 +1,275 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use argon2::Argon2;
+use bdk_wallet::bitcoin::{bip32::Xpriv, secp256k1::Secp256k1};
+use bip39::{Language, Mnemonic};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{
+    config::config,
+    payjoin::store::{load_json, now, save_private_json},
+};
+
+const SEEDS_FILE: &str = "seeds.json";
+const PASSWORD_ENV: &str = "PAYJOIN_POC_SEED_PASSWORD";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Password of this run, asked once.
+static PASSWORD: Mutex<Option<Zeroizing<String>>> = Mutex::new(None);
+
+pub type Seed = Zeroizing<[u8; 64]>;
+
+/// A mnemonic at rest: XChaCha20-Poly1305 under a key derived (Argon2id) from the password.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSeed {
+    name: String,
+    /// Master key fingerprint, to tell the seeds apart without the password
+    fingerprint: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+    created_at: u64,
+}
+
+/// What gets encrypted.
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SeedSecret {
+    mnemonic: String,
+    passphrase: String,
+}
+
+fn seeds_path() -> PathBuf {
+    config().data_dir.join(SEEDS_FILE)
+}
+
+/// Regtest demo seed, `byte` repeated: what the flows use when no stored seed is given.
+pub fn test_seed(byte: u8) -> Seed {
+    Zeroizing::new([byte; 64])
+}
+
+/// Seed of a flow participant and the label its wallet is stored under: the stored seed `name`,
+/// or the test seed of `byte`.
+pub fn participant_seed(
+    name: Option<&str>,
+    byte: u8,
+) -> Result<(String, Seed), Box<dyn std::error::Error>> {
+    match name {
+        Some(name) => Ok((name.to_string(), load_seed(name)?)),
+        None => Ok((byte.to_string(), test_seed(byte))),
+    }
+}
+
+/// Seed of the node `idx`: `<prefix>-<idx>` from the store (generated the first time), or the
+/// test seed of `idx` without a prefix.
+pub fn node_seed(
+    prefix: Option<&str>,
+    idx: u8,
+) -> Result<(String, Seed), Box<dyn std::error::Error>> {
+    let Some(prefix) = prefix else {
+        return Ok((idx.to_string(), test_seed(idx)));
+    };
+    let name = format!("{}-{}", prefix, idx);
+    if find(&name)?.is_none() {
+        let mnemonic = new_mnemonic(24)?;
+        store(&name, &mnemonic, Zeroizing::new(String::new()))?;
+        println!(
+            "[Seed] Generated {}, back it up with `seed show {}`",
+            name, name
+        );
+    }
+    let seed = load_seed(&name)?;
+    Ok((name, seed))
+}
+
+/// Generates a mnemonic of `words` words and stores it, returns it to be written down.
+pub fn generate(
+    name: &str,
+    words: usize,
+    passphrase: bool,
+) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let mnemonic = new_mnemonic(words)?;
+    let passphrase = if passphrase {
+        prompt_new("BIP39 passphrase")?
+    } else {
+        Zeroizing::new(String::new())
+    };
+    let phrase = Zeroizing::new(mnemonic.to_string());
+    store(name, &mnemonic, passphrase)?;
+    Ok(phrase)
+}
+
+/// Stores an existing mnemonic, prompted for so it doesn't end up in the shell history.
+pub fn import(name: &str, passphrase: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let phrase = Zeroizing::new(rpassword::prompt_password("Mnemonic: ")?);
+    let mnemonic = Zeroizing::new(
+        Mnemonic::parse_in_normalized(Language::English, phrase.trim())
+            .map_err(|e| format!("Invalid mnemonic: {}", e))?,
+    );
+    let passphrase = if passphrase {
+        prompt_new("BIP39 passphrase")?
+    } else {
+        Zeroizing::new(String::new())
+    };
+    store(name, &mnemonic, passphrase)
+}
+
+/// Decrypts the mnemonic and passphrase of `name`.
+pub fn show(
+    name: &str,
+) -> Result<(Zeroizing<String>, Zeroizing<String>), Box<dyn std::error::Error>> {
+    let secret = decrypt(name)?;
+    Ok((
+        Zeroizing::new(secret.mnemonic.clone()),
+        Zeroizing::new(secret.passphrase.clone()),
+    ))
+}
+
+/// Name, fingerprint and creation time of the stored seeds.
+pub fn list() -> Result<Vec<(String, String, u64)>, Box<dyn std::error::Error>> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let seeds: Vec<StoredSeed> = load_json(&seeds_path())?;
+    Ok(seeds
+        .into_iter()
+        .map(|seed| (seed.name, seed.fingerprint, seed.created_at))
+        .collect())
+}
+
+/// BIP39 seed (mnemonic + passphrase) of the stored seed `name`.
+pub fn load_seed(name: &str) -> Result<Seed, Box<dyn std::error::Error>> {
+    let secret = decrypt(name)?;
+    let mnemonic = Zeroizing::new(
+        Mnemonic::parse_in_normalized(Language::English, &secret.mnemonic)
+            .map_err(|e| format!("Stored mnemonic {} is invalid: {}", name, e))?,
+    );
+    Ok(Zeroizing::new(mnemonic.to_seed(&secret.passphrase)))
+}
+
+fn new_mnemonic(words: usize) -> Result<Zeroizing<Mnemonic>, Box<dyn std::error::Error>> {
+    if !matches!(words, 12 | 15 | 18 | 21 | 24) {
+        return Err(format!("A mnemonic has 12, 15, 18, 21 or 24 words, not {}", words).into());
+    }
+    // 32 bits of entropy per 3 words
+    let mut entropy = Zeroizing::new(vec![0u8; words / 3 * 4]);
+    rand::thread_rng().fill_bytes(&mut entropy);
+    Ok(Zeroizing::new(Mnemonic::from_entropy(&entropy)?))
+}
+
+fn find(name: &str) -> Result<Option<StoredSeed>, Box<dyn std::error::Error>> {
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let seeds: Vec<StoredSeed> = load_json(&seeds_path())?;
+    Ok(seeds.into_iter().find(|seed| seed.name == name))
+}
+
+fn store(
+    name: &str,
+    mnemonic: &Mnemonic,
+    passphrase: Zeroizing<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if find(name)?.is_some() {
+        return Err(format!("There is already a seed named {}", name).into());
+    }
+    let seed = Zeroizing::new(mnemonic.to_seed(passphrase.as_str()));
+    let fingerprint = Xpriv::new_master(config().network, &seed[..])?
+        .fingerprint(&Secp256k1::new())
+        .to_string();
+
+    let secret = SeedSecret {
+        mnemonic: mnemonic.to_string(),
+        passphrase: passphrase.to_string(),
+    };
+    let plaintext = Zeroizing::new(serde_json::to_vec(&secret)?);
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*derive_key(&salt, true)?));
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| "Failed to encrypt the seed")?;
+
+    let _lock = FILE_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut seeds: Vec<StoredSeed> = load_json(&seeds_path())?;
+    seeds.push(StoredSeed {
+        name: name.to_string(),
+        fingerprint: fingerprint.clone(),
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+        created_at: now(),
+    });
+    save_private_json(&seeds_path(), &seeds)?;
+    println!("[Seed] Stored {} (fingerprint {})", name, fingerprint);
+    Ok(())
+}
+
+fn decrypt(name: &str) -> Result<SeedSecret, Box<dyn std::error::Error>> {
+    let stored = find(name)?.ok_or_else(|| {
+        format!(
+            "No seed named {}, create one with `seed generate {}` or `seed import {}`",
+            name, name, name
+        )
+    })?;
+    let salt = hex::decode(&stored.salt)?;
+    let nonce = hex::decode(&stored.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(format!("Seed {} has a malformed nonce", name).into());
+    }
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*derive_key(&salt, false)?));
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                hex::decode(&stored.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| {
+                format!(
+                    "Wrong password for seed {} (or the file is corrupted)",
+                    name
+                )
+            })?,
+    );
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// 32 bytes key from the password of this run, asked for (twice when `confirm`) the first time.
+fn derive_key(
+    salt: &[u8],
+    confirm: bool,
+) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    let mut password = PASSWORD.lock().map_err(|e| e.to_string())?;
+    if password.is_none() {
+        *password = Some(match std::env::var(PASSWORD_ENV) {
+            Ok(value) => Zeroizing::new(value),
+            Err(_) if confirm => prompt_new("Seed password")?,
+            Err(_) => Zeroizing::new(rpassword::prompt_password("Seed password: ")?),
+        });
+    }
+    let password = password.as_ref().ok_or("No seed password")?;
+    if password.is_empty() {
+        return Err("The seed password can't be empty".into());
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut *key)
+        .map_err(|e| format!("Failed to derive the seed key: {}", e))?;
+    Ok(key)
+}
+
+fn prompt_new(what: &str) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let value = Zeroizing::new(rpassword::prompt_password(format!("{}: ", what))?);
+    let again = Zeroizing::new(rpassword::prompt_password(format!("{} (again): ", what))?);
+    if value != again {
+        return Err(format!("{}s don't match", what).into());
+    }
+    Ok(value)
+}
@@ -1,30 +1,313 @@
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+};
+
 use bdk_wallet::{
     bitcoin::{
         bip32::Xpriv,
         key::rand::{thread_rng, Rng},
-        Amount, Network,
+        psbt::Input,
+        secp256k1::Secp256k1,
+        Amount, Network, TxIn, Txid,
     },
-    template::Bip84,
-    KeychainKind, LocalOutput, Wallet,
+    descriptor::calc_checksum,
+    miniscript::{Descriptor, DescriptorPublicKey},
+    rusqlite::Connection,
+    KeychainKind, LocalOutput, PersistedWallet, Wallet,
 };
 
+use bdk_bitcoind_rpc::Emitter;
 use bitcoincore_rpc::{Client, RpcApi};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{config, ChainSource},
+    filters::{self, sync_with_filters},
+    payjoin::store::now,
+    seed::participant_seed,
+};
+
+const WALLETS_DIR: &str = "wallets";
+
+/// A bdk wallet backed by `<data_dir>/wallets/<name>.sqlite`. Derefs to the `Wallet`, pending
+/// changes (revealed addresses, synced blocks, txs) are written by `persist` and on drop.
+pub struct StoredWallet {
+    name: String,
+    wallet: PersistedWallet<Connection>,
+    conn: Connection,
+}
+
+impl StoredWallet {
+    pub fn persist(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.wallet
+            .persist(&mut self.conn)
+            .map_err(|e| format!("Failed to persist wallet {}: {}", self.name, e))?;
+        Ok(())
+    }
+}
+
+impl Deref for StoredWallet {
+    type Target = Wallet;
+
+    fn deref(&self) -> &Wallet {
+        &self.wallet
+    }
+}
+
+impl DerefMut for StoredWallet {
+    fn deref_mut(&mut self) -> &mut Wallet {
+        &mut self.wallet
+    }
+}
 
-pub fn create_wallet(seed_bytes: &[u8]) -> Result<Wallet, Box<dyn std::error::Error>> {
-    let network = Network::Signet;
+impl Drop for StoredWallet {
+    fn drop(&mut self) {
+        if let Err(err) = self.persist() {
+            println!("ERROR(persist): {}", err);
+        }
+    }
+}
+
+fn wallet_path(name: &str) -> PathBuf {
+    config()
+        .data_dir
+        .join(WALLETS_DIR)
+        .join(format!("{}.sqlite", name))
+}
+
+/// Script type of the wallets derived from a seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScriptType {
+    /// BIP84 native segwit, wpkh()
+    P2wpkh,
+    /// BIP86 taproot key path, tr()
+    P2tr,
+    /// BIP49 nested segwit, sh(wpkh())
+    P2shP2wpkh,
+    /// 2-of-3 wsh(multi()), the three keys (BIP48 accounts 0-2) come from the same seed
+    P2wshMulti,
+}
+
+impl ScriptType {
+    pub const ALL: [ScriptType; 4] = [
+        ScriptType::P2wpkh,
+        ScriptType::P2tr,
+        ScriptType::P2shP2wpkh,
+        ScriptType::P2wshMulti,
+    ];
+
+    /// External and internal (change) descriptors, with the private keys.
+    fn descriptors(self, xprv: &Xpriv, network: Network) -> (Zeroizing<String>, Zeroizing<String>) {
+        let coin = if network == Network::Bitcoin { 0 } else { 1 };
+        let descriptor = |keychain: u32| {
+            Zeroizing::new(match self {
+                ScriptType::P2wpkh => format!("wpkh({}/84'/{}'/0'/{}/*)", xprv, coin, keychain),
+                ScriptType::P2tr => format!("tr({}/86'/{}'/0'/{}/*)", xprv, coin, keychain),
+                ScriptType::P2shP2wpkh => {
+                    format!("sh(wpkh({}/49'/{}'/0'/{}/*))", xprv, coin, keychain)
+                }
+                ScriptType::P2wshMulti => {
+                    let keys: Zeroizing<Vec<String>> = Zeroizing::new(
+                        (0..3)
+                            .map(|account| {
+                                format!("{}/48'/{}'/{}'/2'/{}/*", xprv, coin, account, keychain)
+                            })
+                            .collect(),
+                    );
+                    let keys = Zeroizing::new(keys.join(","));
+                    format!("wsh(multi(2,{}))", keys.as_str())
+                }
+            })
+        };
+        (descriptor(0), descriptor(1))
+    }
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::P2shP2wpkh => "p2sh-p2wpkh",
+            ScriptType::P2wshMulti => "p2wsh-multi",
+        })
+    }
+}
+
+/// Store name of a seed wallet: `<role>-<seed label>`, suffixed with the script type unless P2WPKH.
+pub fn wallet_name(role: &str, seed: &str, script_type: ScriptType) -> String {
+    match script_type {
+        ScriptType::P2wpkh => format!("{}-{}", role, seed),
+        other => format!("{}-{}-{}", role, seed, other),
+    }
+}
 
-    let xprv = Xpriv::new_master(network, seed_bytes)
+/// Loads the wallet called `name`, or creates it from `seed_bytes` the first time. The stored
+/// descriptors must match the seed's.
+///
+/// NOTE: the descriptor strings are wiped, but not every copy of the keys: `Xpriv` is `Copy`
+/// and has no `Zeroize`, bdk only takes descriptors as an owned `String` (dropped unwiped once
+/// parsed) and keeps the keys in its signers for the wallet's lifetime.
+pub fn create_wallet(
+    name: &str,
+    seed_bytes: &[u8],
+    script_type: ScriptType,
+) -> Result<StoredWallet, Box<dyn std::error::Error>> {
+    let network = config().network;
+
+    let mut xprv = Xpriv::new_master(network, seed_bytes)
         .map_err(|e| format!("Failed to derive master secret: {}", e))?;
 
-    let descriptor = Bip84(xprv, KeychainKind::External);
-    let change_descriptor = Bip84(xprv, KeychainKind::Internal);
+    let (descriptor, change_descriptor) = script_type.descriptors(&xprv, network);
+    xprv.private_key.non_secure_erase();
+    load_or_create(name, descriptor, Some(change_descriptor))
+}
+
+/// Wallet of a flow participant, from its stored seed `seed_name` or the test seed of `seed_byte`.
+pub fn participant_wallet(
+    role: &str,
+    seed_name: Option<&str>,
+    seed_byte: u8,
+    script_type: ScriptType,
+) -> Result<StoredWallet, Box<dyn std::error::Error>> {
+    let (label, seed) = participant_seed(seed_name, seed_byte)?;
+    create_wallet(
+        &wallet_name(role, &label, script_type),
+        &seed[..],
+        script_type,
+    )
+}
+
+/// Descriptor given on the command line: `@<file>` reads it from the file, `-` prompts for it.
+/// Inline ones must be public, argv ends up in the shell history and `ps`.
+pub fn read_descriptor(arg: &str) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let descriptor = if arg == "-" {
+        Zeroizing::new(rpassword::prompt_password("Descriptor: ")?)
+    } else if let Some(path) = arg.strip_prefix('@') {
+        Zeroizing::new(
+            fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read descriptor from {}: {}", path, e))?,
+        )
+    } else {
+        if has_private_keys(arg)? {
+            return Err(
+                "Private descriptors can't be passed inline, use @<file> or - to be prompted"
+                    .into(),
+            );
+        }
+        Zeroizing::new(arg.to_string())
+    };
+    Ok(Zeroizing::new(descriptor.trim().to_string()))
+}
+
+fn has_private_keys(descriptor: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    // The error would quote the keys
+    let (_, keys) =
+        Descriptor::<DescriptorPublicKey>::parse_descriptor(&Secp256k1::new(), descriptor)
+            .map_err(|_| "Invalid descriptor")?;
+    Ok(!keys.is_empty())
+}
+
+/// Wallet from an output descriptor, stored under `desc-<checksum>` with private keys or
+/// `watch-<checksum>` for a watch-only one (xpubs). Without a change descriptor the wallet has a
+/// single keychain.
+pub fn create_descriptor_wallet(
+    descriptor: Zeroizing<String>,
+    change_descriptor: Option<Zeroizing<String>>,
+) -> Result<StoredWallet, Box<dyn std::error::Error>> {
+    let checksum = calc_checksum(&descriptor).map_err(|_| "Invalid descriptor")?;
+    let prefix = if has_private_keys(&descriptor)? {
+        "desc"
+    } else {
+        "watch"
+    };
+    load_or_create(
+        &format!("{}-{}", prefix, checksum),
+        descriptor,
+        change_descriptor,
+    )
+}
+
+fn load_or_create(
+    name: &str,
+    descriptor: Zeroizing<String>,
+    change_descriptor: Option<Zeroizing<String>>,
+) -> Result<StoredWallet, Box<dyn std::error::Error>> {
+    let network = config().network;
+
+    let path = wallet_path(name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut conn = Connection::open(&path)
+        .map_err(|e| format!("Failed to open wallet store {:?}: {}", path, e))?;
+
+    // bdk wants owned descriptors, these copies are dropped unwiped
+    let mut params =
+        Wallet::load().descriptor(KeychainKind::External, Some(descriptor.to_string()));
+    if let Some(change_descriptor) = &change_descriptor {
+        params = params.descriptor(KeychainKind::Internal, Some(change_descriptor.to_string()));
+    }
+    let loaded = params
+        .extract_keys()
+        .check_network(network)
+        .load_wallet(&mut conn)
+        .map_err(|e| format!("Failed to load wallet {}: {}", name, e))?;
+    let wallet = match loaded {
+        Some(wallet) => {
+            println!(
+                "[Wallet] Loaded {} (synced to {})",
+                name,
+                wallet.latest_checkpoint().height()
+            );
+            wallet
+        }
+        None => {
+            println!("[Wallet] Created {} at {:?}", name, path);
+            let params = match &change_descriptor {
+                Some(change_descriptor) => {
+                    Wallet::create(descriptor.to_string(), change_descriptor.to_string())
+                }
+                None => Wallet::create_single(descriptor.to_string()),
+            };
+            params
+                .network(network)
+                .create_wallet(&mut conn)
+                .map_err(|e| format!("Failed to set up wallet: {}", e))?
+        }
+    };
+    if wallet
+        .get_signers(KeychainKind::External)
+        .signers()
+        .is_empty()
+    {
+        println!("[Wallet] {} is watch-only", name);
+    }
 
-    let wallet = Wallet::create(descriptor, change_descriptor)
-        .network(network)
-        .create_wallet_no_persist()
-        .map_err(|e| format!("Failed to set up wallet: {}", e))?;
+    Ok(StoredWallet {
+        name: name.to_string(),
+        wallet,
+        conn,
+    })
+}
 
-    Ok(wallet)
+/// Input spending our `utxo`, with the PSBT fields the other participants need: UTXOs, redeem or
+/// witness script, key origins. Whatever the script type, signers and fee checks rely on them.
+pub fn psbt_input(
+    wallet: &Wallet,
+    utxo: &LocalOutput,
+) -> Result<(TxIn, Input), Box<dyn std::error::Error>> {
+    let input = wallet.get_psbt_input(utxo.clone(), None, false)?;
+    let txin = TxIn {
+        previous_output: utxo.outpoint,
+        script_sig: Default::default(),
+        sequence: Default::default(),
+        witness: Default::default(),
+    };
+    Ok((txin, input))
 }
 
 pub fn fund_wallet(
@@ -54,26 +337,102 @@ pub fn fund_wallet(
     Ok(())
 }
 
+/// Blocks between two progress lines of a verbose sync.
+pub const SYNC_PROGRESS_INTERVAL: u32 = 100;
+
+/// Brings the wallet to the chain tip through the configured chain source (full blocks or
+/// BIP158 filters), then adds the mempool txs as unconfirmed so pending payjoins count in the
+/// balance right after their broadcast. Unconfirmed txs no longer in the mempool (replaced,
+/// expired, dropped by a restart) are evicted, otherwise the stored wallet would count them forever.
 pub fn sync_wallet(
     client: &Client,
     wallet: &mut Wallet,
     debug: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let latest = client.get_block_count()?;
-    let stored = wallet.latest_checkpoint().block_id().height as u64;
+    let latest = client.get_block_count()? as u32;
     if debug {
         println!(
-            "    -> WalletSyncBlock: (stored={} | latest={})",
-            stored, latest
+            "    -> WalletSyncBlock: (stored={} | latest={} | source={})",
+            wallet.latest_checkpoint().height(),
+            latest,
+            config().chain_source
         );
     }
-    for height in stored..latest {
-        let hash = client.get_block_hash(height)?;
-        let block = client.get_block(&hash)?;
-        wallet.apply_block(&block, height as u32)?;
-    }
+
+    let mempool = match config().chain_source {
+        ChainSource::Rpc => {
+            let mut emitter = Emitter::new(
+                client,
+                wallet.latest_checkpoint(),
+                wallet.latest_checkpoint().height(),
+            );
+            emit_blocks(&mut emitter, wallet, latest, debug)?;
+            emitter.mempool()?
+        }
+        ChainSource::Filters => {
+            sync_with_filters(client, wallet, latest, debug)?;
+            filters::mempool(client)?
+        }
+    };
     if debug {
-        println!("    -> WalletSyncBlock: Done!");
+        println!(
+            "    -> WalletSyncBlock: Done! ({} mempool txs)",
+            mempool.len()
+        );
+    }
+    let in_mempool: HashSet<Txid> = mempool.iter().map(|(tx, _)| tx.compute_txid()).collect();
+    let evicted: Vec<(Txid, u64)> = wallet
+        .transactions()
+        .filter(|tx| !tx.chain_position.is_confirmed())
+        .map(|tx| tx.tx_node.txid)
+        .filter(|txid| !in_mempool.contains(txid))
+        .map(|txid| (txid, now()))
+        .collect();
+    if debug && !evicted.is_empty() {
+        println!(
+            "    -> WalletSyncBlock: Evicting {} txs gone from the mempool",
+            evicted.len()
+        );
+    }
+    wallet.apply_unconfirmed_txs(mempool);
+    wallet.apply_evicted_txs(evicted);
+    Ok(())
+}
+
+/// Applies every block after the wallet's checkpoint, the emitter rolls back to the fork point
+/// when the chain reorged.
+fn emit_blocks(
+    emitter: &mut Emitter<'_, Client>,
+    wallet: &mut Wallet,
+    latest: u32,
+    debug: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let checkpoint = wallet.latest_checkpoint();
+    let stored = checkpoint.height();
+    let mut applied = 0;
+    let mut reorged = false;
+    while let Some(emission) = emitter.next_block()? {
+        let height = emission.block_height();
+        let connected_to = emission.connected_to();
+        // A new block at or below our tip: the emitter went back to the fork point
+        if !reorged
+            && height <= stored
+            && checkpoint.get(height).map(|cp| cp.hash()) != Some(emission.block_hash())
+        {
+            reorged = true;
+            println!(
+                "    -> WalletSyncBlock: Reorg! Rolling back to {} (tip was {})",
+                connected_to.height, stored
+            );
+        }
+        wallet.apply_block_connected_to(&emission.block, height, connected_to)?;
+        applied += 1;
+        if debug && (applied % SYNC_PROGRESS_INTERVAL == 0 || height == latest) {
+            println!(
+                "    -> WalletSyncBlock: {}/{} ({} blocks)",
+                height, latest, applied
+            );
+        }
     }
     Ok(())
 }
@@ -0,0 +1,549 @@
+use std::fmt;
+
+use bdk_wallet::{
+    bitcoin::{
+        opcodes::{
+            all::{OP_CHECKMULTISIG, OP_NUMEQUAL},
+            Class, ClassifyContext,
+        },
+        psbt::Input,
+        script::Instruction,
+        transaction::{predict_weight, InputWeightPrediction},
+        Amount, FeeRate, Psbt, Script, TapSighashType, TxIn, TxOut, Weight, XOnlyPublicKey,
+    },
+    miniscript::{descriptor::DescriptorType, Descriptor, DescriptorPublicKey, ToPublicKey},
+};
+
+// BIP341 "nothing up my sleeve" internal key, taproot descriptors using it can only be script spent
+const NUMS_INTERNAL_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+// scriptSig of a nested segwit input: push of the 22 bytes P2WPKH (or 34 bytes P2WSH) program
+const P2SH_P2WPKH_SCRIPT_SIG: usize = 1 + 22;
+const P2SH_P2WSH_SCRIPT_SIG: usize = 1 + 34;
+
+/// Size of the ECDSA signatures (DER + sighash byte) the predictions assume.
+#[derive(Debug, Clone, Copy)]
+pub enum Signatures {
+    /// 72 bytes, what bdk (miniscript) and rust-bitcoin assume
+    Max,
+    /// 71 bytes, bitcoind grinds low-R signatures and estimates its fees with them
+    LowR,
+}
+
+impl Signatures {
+    fn ecdsa(self) -> usize {
+        match self {
+            Signatures::Max => 72,
+            Signatures::LowR => 71,
+        }
+    }
+}
+
+/// Weight of the tx once every input is signed: finalized inputs are taken as they are, the
+/// others are predicted from their PSBT fields (see `predict_input`).
+pub fn predict_psbt_weight(
+    psbt: &Psbt,
+    signatures: Signatures,
+) -> Result<Weight, Box<dyn std::error::Error>> {
+    if psbt.inputs.len() != psbt.unsigned_tx.input.len() {
+        return Err("PSBT inputs don't match the unsigned tx".into());
+    }
+    let inputs = psbt
+        .inputs
+        .iter()
+        .zip(&psbt.unsigned_tx.input)
+        .map(|(input, txin)| predict_input(input, txin, signatures))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(predict_weight(
+        inputs,
+        psbt.unsigned_tx.script_pubkey_lens(),
+    ))
+}
+
+/// Predicts how the input will be satisfied from the script it spends and the PSBT fields:
+/// P2PKH, P2SH-P2WPKH, P2WPKH, (P2SH-)P2WSH multisig, P2TR key path and P2TR script path
+/// (single key and multi_a leaves, from `tap_scripts`).
+pub fn predict_input(
+    input: &Input,
+    txin: &TxIn,
+    signatures: Signatures,
+) -> Result<InputWeightPrediction, Box<dyn std::error::Error>> {
+    if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+        return Ok(InputWeightPrediction::new(
+            input
+                .final_script_sig
+                .as_ref()
+                .map_or(0, |script| script.len()),
+            input
+                .final_script_witness
+                .iter()
+                .flat_map(|witness| witness.iter().map(|element| element.len())),
+        ));
+    }
+
+    let prevout = spent_output(input, txin)?;
+    let script = &prevout.script_pubkey;
+    let ecdsa = signatures.ecdsa();
+    if script.is_p2pkh() {
+        // <signature> <compressed pubkey>
+        Ok(InputWeightPrediction::new(1 + ecdsa + 1 + 33, [0usize; 0]))
+    } else if script.is_p2wpkh() {
+        Ok(InputWeightPrediction::new(0, [ecdsa, 33]))
+    } else if script.is_p2sh() {
+        match &input.redeem_script {
+            Some(redeem) if redeem.is_p2wpkh() => Ok(InputWeightPrediction::new(
+                P2SH_P2WPKH_SCRIPT_SIG,
+                [ecdsa, 33],
+            )),
+            Some(redeem) if redeem.is_p2wsh() => {
+                let witness_script = input
+                    .witness_script
+                    .as_ref()
+                    .ok_or("P2SH-P2WSH input without its witness script")?;
+                multisig(witness_script, P2SH_P2WSH_SCRIPT_SIG, ecdsa)
+            }
+            Some(redeem) => Err(format!("Unsupported P2SH redeem script {}", redeem).into()),
+            None => Err(format!(
+                "P2SH input {} without its redeem script",
+                txin.previous_output
+            )
+            .into()),
+        }
+    } else if script.is_p2wsh() {
+        let witness_script = input
+            .witness_script
+            .as_ref()
+            .ok_or("P2WSH input without its witness script")?;
+        multisig(witness_script, 0, ecdsa)
+    } else if script.is_p2tr() {
+        let schnorr = match input.sighash_type.map(|ty| ty.taproot_hash_ty()) {
+            None | Some(Ok(TapSighashType::Default)) => 64,
+            _ => 65,
+        };
+        // Key path when there are no leaves, or when the internal key is one of ours
+        let key_path = input.tap_scripts.is_empty()
+            || input
+                .tap_internal_key
+                .is_some_and(|key| input.tap_key_origins.contains_key(&key));
+        if key_path {
+            return Ok(InputWeightPrediction::new(0, [schnorr]));
+        }
+        let leaves = input
+            .tap_scripts
+            .iter()
+            .map(|(control_block, (script, _))| (script.as_script(), control_block.size()));
+        tapscript(leaves, schnorr)
+    } else {
+        Err(format!(
+            "Unsupported script {} spent by {}",
+            script, txin.previous_output
+        )
+        .into())
+    }
+}
+
+/// Same as `predict_input`, for an input of a wallet with this descriptor (before there is a PSBT).
+pub fn predict_from_descriptor(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    signatures: Signatures,
+) -> Result<InputWeightPrediction, Box<dyn std::error::Error>> {
+    // Any index does, the script shapes are the same
+    let descriptor = descriptor.at_derivation_index(0)?;
+    let ecdsa = signatures.ecdsa();
+
+    if let Descriptor::Tr(tr) = &descriptor {
+        let internal_key = XOnlyPublicKey::from(tr.internal_key().to_public_key().inner);
+        if internal_key.to_string() != NUMS_INTERNAL_KEY {
+            return Ok(InputWeightPrediction::new(0, [64]));
+        }
+        let spend_info = tr.spend_info();
+        let leaves: Vec<_> = spend_info
+            .script_map()
+            .iter()
+            .flat_map(|((script, _), branches)| {
+                branches
+                    .iter()
+                    .map(move |branch| (script.as_script(), 33 + 32 * branch.len()))
+            })
+            .collect();
+        return tapscript(leaves, 64);
+    }
+
+    match descriptor.desc_type() {
+        DescriptorType::Pkh => Ok(InputWeightPrediction::new(1 + ecdsa + 1 + 33, [0usize; 0])),
+        DescriptorType::Wpkh => Ok(InputWeightPrediction::new(0, [ecdsa, 33])),
+        DescriptorType::ShWpkh => Ok(InputWeightPrediction::new(
+            P2SH_P2WPKH_SCRIPT_SIG,
+            [ecdsa, 33],
+        )),
+        DescriptorType::Wsh | DescriptorType::WshSortedMulti => {
+            multisig(&descriptor.explicit_script()?, 0, ecdsa)
+        }
+        DescriptorType::ShWsh | DescriptorType::ShWshSortedMulti => {
+            multisig(&descriptor.explicit_script()?, P2SH_P2WSH_SCRIPT_SIG, ecdsa)
+        }
+        other => Err(format!("Unsupported descriptor type {:?}", other).into()),
+    }
+}
+
+/// Output the input spends, from its witness or non-witness UTXO.
+pub fn spent_output<'a>(
+    input: &'a Input,
+    txin: &TxIn,
+) -> Result<&'a TxOut, Box<dyn std::error::Error>> {
+    if let Some(witness_utxo) = &input.witness_utxo {
+        return Ok(witness_utxo);
+    }
+    input
+        .non_witness_utxo
+        .as_ref()
+        .and_then(|tx| tx.output.get(txin.previous_output.vout as usize))
+        .ok_or_else(|| format!("No UTXO info for input {}", txin.previous_output).into())
+}
+
+/// `OP_m <pubkey>... OP_n OP_CHECKMULTISIG` witness script: empty element (CHECKMULTISIG bug),
+/// `m` signatures and the script.
+fn multisig(
+    witness_script: &Script,
+    script_sig: usize,
+    ecdsa: usize,
+) -> Result<InputWeightPrediction, Box<dyn std::error::Error>> {
+    let instructions = witness_script
+        .instructions()
+        .collect::<Result<Vec<_>, _>>()?;
+    let threshold = match (instructions.first(), instructions.last()) {
+        (Some(Instruction::Op(m)), Some(Instruction::Op(op))) if *op == OP_CHECKMULTISIG => {
+            match m.classify(ClassifyContext::Legacy) {
+                Class::PushNum(m) if m > 0 => m as usize,
+                _ => 0,
+            }
+        }
+        _ => 0,
+    };
+    if threshold == 0 {
+        return Err(format!(
+            "Unsupported witness script {}, only multisig",
+            witness_script
+        )
+        .into());
+    }
+    let mut witness = vec![0];
+    witness.extend(std::iter::repeat(ecdsa).take(threshold));
+    witness.push(witness_script.len());
+    Ok(InputWeightPrediction::new(script_sig, witness))
+}
+
+/// Heaviest of the leaves: its signatures, empty elements for the keys that don't sign, the
+/// script and the control block. Single key (`<pk> OP_CHECKSIG`) and multi_a
+/// (`<pk> OP_CHECKSIG <pk> OP_CHECKSIGADD ... <k> OP_NUMEQUAL`) leaves get their exact threshold,
+/// other leaves are assumed to need every key they push.
+fn tapscript<'a>(
+    leaves: impl IntoIterator<Item = (&'a Script, usize)>,
+    schnorr: usize,
+) -> Result<InputWeightPrediction, Box<dyn std::error::Error>> {
+    let mut heaviest: Option<InputWeightPrediction> = None;
+    for (script, control_block) in leaves {
+        let instructions = script.instructions().collect::<Result<Vec<_>, _>>()?;
+        let keys = instructions
+            .iter()
+            .filter(|instruction| matches!(instruction, Instruction::PushBytes(bytes) if bytes.len() == 32))
+            .count();
+        if keys == 0 {
+            return Err(format!("Unsupported tapscript {}, no keys", script).into());
+        }
+        let threshold = match instructions.as_slice() {
+            [.., Instruction::Op(k), Instruction::Op(op)] if *op == OP_NUMEQUAL => {
+                match k.classify(ClassifyContext::TapScript) {
+                    Class::PushNum(k) if k > 0 => (k as usize).min(keys),
+                    _ => keys,
+                }
+            }
+            _ => keys,
+        };
+        let mut witness = vec![schnorr; threshold];
+        witness.extend(std::iter::repeat(0).take(keys - threshold));
+        witness.push(script.len());
+        witness.push(control_block);
+        let prediction = InputWeightPrediction::new(0, witness);
+        match &heaviest {
+            Some(heaviest) if heaviest.weight() >= prediction.weight() => {}
+            _ => heaviest = Some(prediction),
+        }
+    }
+    heaviest.ok_or_else(|| "Taproot input without a key path nor leaves".into())
+}
+
+/// Fee a PSBT pays against its predicted (signed) weight.
+#[derive(Debug, Clone, Copy)]
+pub struct FeeCheck {
+    pub fee: Amount,
+    pub weight: Weight,
+}
+
+impl FeeCheck {
+    pub fn new(
+        psbt: &Psbt,
+        signatures: Signatures,
+    ) -> Result<FeeCheck, Box<dyn std::error::Error>> {
+        Ok(FeeCheck {
+            fee: psbt.fee()?,
+            weight: predict_psbt_weight(psbt, signatures)?,
+        })
+    }
+
+    /// What the fee misses to reach `fee_rate`, zero when it does.
+    pub fn missing(&self, fee_rate: FeeRate) -> Result<Amount, Box<dyn std::error::Error>> {
+        let required = fee_rate.fee_wu(self.weight).ok_or("Fee overflow")?;
+        Ok(required.checked_sub(self.fee).unwrap_or(Amount::ZERO))
+    }
+
+    /// Errors when the fee doesn't reach `fee_rate` for the predicted weight.
+    pub fn ensure(&self, fee_rate: FeeRate) -> Result<(), Box<dyn std::error::Error>> {
+        let missing = self.missing(fee_rate)?;
+        if missing > Amount::ZERO {
+            return Err(format!(
+                "Fee {} is {} short of {} sat/vB ({})",
+                self.fee,
+                missing,
+                fee_rate.to_sat_per_vb_ceil(),
+                self
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FeeCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vbytes = self.weight.to_vbytes_ceil();
+        write!(
+            f,
+            "fee={} | predicted vsize={} vB | fee_rate={:.2} sat/vB",
+            self.fee,
+            vbytes,
+            self.fee.to_sat() as f64 / vbytes as f64
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bdk_wallet::{
+        bitcoin::{
+            absolute::LockTime,
+            bip32::{Xpriv, Xpub},
+            hashes::Hash,
+            secp256k1::Secp256k1,
+            transaction::Version,
+            Network, OutPoint, ScriptBuf, Transaction, Txid, WPubkeyHash,
+        },
+        KeychainKind, SignOptions, Wallet,
+    };
+
+    use super::*;
+
+    fn xprv(byte: u8) -> Xpriv {
+        Xpriv::new_master(Network::Regtest, &[byte; 64]).unwrap()
+    }
+
+    fn xpub(byte: u8) -> Xpub {
+        Xpub::from_priv(&Secp256k1::new(), &xprv(byte))
+    }
+
+    /// Funds a wallet with `descriptor` and spends the UTXO: the wallet, the input before signing,
+    /// its txin and the weight of the signed input.
+    fn spend(descriptor: String) -> (Wallet, Input, TxIn, Weight) {
+        let mut wallet = Wallet::create_single(descriptor)
+            .network(Network::Regtest)
+            .create_wallet_no_persist()
+            .unwrap();
+        let address = wallet.reveal_next_address(KeychainKind::External).address;
+        let funding = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        wallet.apply_unconfirmed_txs([(funding, 1)]);
+
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_wallet()
+            .drain_to(ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()));
+        let mut psbt = builder.finish().unwrap();
+        let unsigned = psbt.inputs[0].clone();
+        assert!(wallet.sign(&mut psbt, SignOptions::default()).unwrap());
+
+        let signed = &psbt.inputs[0];
+        let actual = InputWeightPrediction::new(
+            signed
+                .final_script_sig
+                .as_ref()
+                .map_or(0, |script| script.len()),
+            signed
+                .final_script_witness
+                .iter()
+                .flat_map(|witness| witness.iter().map(|element| element.len())),
+        )
+        .weight();
+        let txin = psbt.unsigned_tx.input[0].clone();
+        (wallet, unsigned, txin, actual)
+    }
+
+    /// Both predictions agree, and match the signed input up to the low-R signatures that came
+    /// out a byte shorter.
+    fn assert_predicted(descriptor: String, ecdsa_signatures: u64) {
+        let (wallet, unsigned, txin, actual) = spend(descriptor.clone());
+        let from_psbt = predict_input(&unsigned, &txin, Signatures::LowR)
+            .unwrap()
+            .weight();
+        let from_descriptor = predict_from_descriptor(
+            wallet.public_descriptor(KeychainKind::External),
+            Signatures::LowR,
+        )
+        .unwrap()
+        .weight();
+        assert_eq!(from_psbt, from_descriptor, "{}", descriptor);
+        assert!(
+            from_psbt >= actual && from_psbt - actual <= Weight::from_wu(ecdsa_signatures),
+            "{}: predicted {}, signed {}",
+            descriptor,
+            from_psbt,
+            actual
+        );
+        let max = predict_input(&unsigned, &txin, Signatures::Max)
+            .unwrap()
+            .weight();
+        assert_eq!(max - from_psbt, Weight::from_wu(ecdsa_signatures));
+    }
+
+    #[test]
+    fn p2pkh() {
+        assert_predicted(format!("pkh({}/44'/1'/0'/0/*)", xprv(1)), 1);
+    }
+
+    #[test]
+    fn p2wpkh() {
+        assert_predicted(format!("wpkh({}/84'/1'/0'/0/*)", xprv(1)), 1);
+    }
+
+    #[test]
+    fn p2sh_p2wpkh() {
+        assert_predicted(format!("sh(wpkh({}/49'/1'/0'/0/*))", xprv(1)), 1);
+    }
+
+    #[test]
+    fn p2wsh_multi() {
+        assert_predicted(
+            format!(
+                "wsh(multi(2,{}/0/*,{}/0/*,{}/0/*))",
+                xprv(1),
+                xprv(2),
+                xprv(3)
+            ),
+            2,
+        );
+    }
+
+    #[test]
+    fn p2sh_p2wsh_multi() {
+        assert_predicted(
+            format!("sh(wsh(multi(2,{}/0/*,{}/0/*)))", xprv(1), xprv(2)),
+            2,
+        );
+    }
+
+    #[test]
+    fn p2tr_key_path() {
+        assert_predicted(format!("tr({}/86'/1'/0'/0/*)", xprv(1)), 0);
+    }
+
+    #[test]
+    fn p2tr_script_path() {
+        assert_predicted(format!("tr({},pk({}/0/*))", NUMS_INTERNAL_KEY, xprv(1)), 0);
+        assert_predicted(
+            format!(
+                "tr({},multi_a(2,{}/0/*,{}/0/*,{}/0/*))",
+                NUMS_INTERNAL_KEY,
+                xprv(1),
+                xprv(2),
+                xprv(3)
+            ),
+            0,
+        );
+    }
+
+    #[test]
+    fn missing_spending_info() {
+        let (_, unsigned, txin, _) =
+            spend(format!("sh(wsh(multi(2,{}/0/*,{}/0/*)))", xprv(1), xprv(2)));
+        let predict = |input: Input| predict_input(&input, &txin, Signatures::Max);
+
+        let mut input = unsigned.clone();
+        input.witness_utxo = None;
+        input.non_witness_utxo = None;
+        assert!(predict(input).is_err());
+
+        let mut input = unsigned.clone();
+        input.redeem_script = None;
+        assert!(predict(input).is_err());
+
+        let mut input = unsigned;
+        input.witness_script = None;
+        assert!(predict(input).is_err());
+    }
+
+    #[test]
+    fn unsupported_scripts() {
+        let txin = TxIn::default();
+        let spending = |script_pubkey: ScriptBuf| Input {
+            witness_utxo: Some(TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey,
+            }),
+            ..Default::default()
+        };
+        let not_multisig = ScriptBuf::new();
+
+        let input = spending(ScriptBuf::new());
+        assert!(predict_input(&input, &txin, Signatures::Max).is_err());
+
+        let mut input = spending(ScriptBuf::new_p2wsh(&not_multisig.wscript_hash()));
+        input.witness_script = Some(not_multisig.clone());
+        assert!(predict_input(&input, &txin, Signatures::Max).is_err());
+
+        let mut input = spending(ScriptBuf::new_p2sh(&not_multisig.script_hash()));
+        input.redeem_script = Some(not_multisig);
+        assert!(predict_input(&input, &txin, Signatures::Max).is_err());
+
+        for descriptor in [
+            format!("sh(multi(1,{}/0/*))", xpub(1)),
+            format!("wsh(pk({}/0/*))", xpub(1)),
+        ] {
+            let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&descriptor).unwrap();
+            assert!(predict_from_descriptor(&descriptor, Signatures::Max).is_err());
+        }
+    }
+
+    #[test]
+    fn psbt_inputs_mismatch() {
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![],
+        })
+        .unwrap();
+        psbt.inputs.clear();
+        assert!(predict_psbt_weight(&psbt, Signatures::Max).is_err());
+    }
+}